@@ -0,0 +1,95 @@
+// Compares the bulk SOF/ESC scanning fast paths against feeding bytes
+// through one at a time.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+use serial_framing_protocol::crc::{Crc, CRC_LEN};
+use serial_framing_protocol::rawpacket::{RawPacketParser, RawParseResult};
+use serial_framing_protocol::traits::{PacketWriter, ESC, SOF};
+use serial_framing_protocol::vecstorage::VecPacketBuffer;
+
+const PAYLOAD_LEN: usize = 1024;
+
+// Returns an escape free payload, and one where one byte in 16 needs
+// escaping.
+fn payloads() -> Vec<(&'static str, Vec<u8>)> {
+    let plain: Vec<u8> = (0..PAYLOAD_LEN).map(|i| (i % 0x70) as u8).collect();
+    let mut sparse = plain.clone();
+    for (i, byte) in sparse.iter_mut().enumerate().step_by(16) {
+        *byte = if i % 32 == 0 { SOF } else { ESC };
+    }
+    vec![("escape_free", plain), ("sparse_escapes", sparse)]
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, payload) in payloads() {
+        let mut frame = Vec::new();
+        frame.write_packet_data(0x00, &payload);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("per_byte", name), &frame, |b, frame| {
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(PAYLOAD_LEN + CRC_LEN);
+            b.iter(|| {
+                for byte in frame.iter() {
+                    let result = parser.parse_byte(*byte, &mut rx_data);
+                    if result != RawParseResult::MoreDataNeeded {
+                        black_box(result);
+                    }
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("bulk", name), &frame, |b, frame| {
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(PAYLOAD_LEN + CRC_LEN);
+            b.iter(|| {
+                let mut idx = 0;
+                while idx < frame.len() {
+                    let (consumed, result) = parser.parse_bytes(&frame[idx..], &mut rx_data);
+                    idx += consumed;
+                    black_box(result);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_escaped");
+    for (name, payload) in payloads() {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        let mut writer: Vec<u8> = Vec::with_capacity(2 * PAYLOAD_LEN);
+
+        group.bench_with_input(
+            BenchmarkId::new("per_byte", name),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    writer.clear();
+                    let mut crc = Crc::new();
+                    for byte in payload.iter() {
+                        writer.write_escaped_byte(&mut crc, *byte);
+                    }
+                    black_box(crc.crc())
+                })
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("bulk", name), &payload, |b, payload| {
+            b.iter(|| {
+                writer.clear();
+                let mut crc = Crc::new();
+                writer.write_escaped_bytes(&mut crc, payload);
+                black_box(crc.crc())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_write);
+criterion_main!(benches);
@@ -0,0 +1,147 @@
+// Throughput baselines for encoding, decoding and EndPoint round trips.
+// Criterion reports each result in bytes per second.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+use serial_framing_protocol::crc::{Crc, CRC_LEN};
+use serial_framing_protocol::encode::{encode_packet, encoded_len_upper_bound};
+use serial_framing_protocol::rawpacket::{RawPacketParser, RawParseResult};
+use serial_framing_protocol::traits::{PacketWriter, ESC, SOF};
+use serial_framing_protocol::vecstorage::{VecPacketBuffer, VecStorage};
+use serial_framing_protocol::{EndPoint, ParseResult};
+
+const PAYLOAD_LEN: usize = 256;
+
+// Returns a payload which needs no escaping, and one where every byte
+// needs escaping.
+fn payloads() -> Vec<(&'static str, Vec<u8>)> {
+    let plain = (0..PAYLOAD_LEN).map(|i| (i % 0x70) as u8).collect();
+    let heavy = (0..PAYLOAD_LEN)
+        .map(|i| if i % 2 == 0 { SOF } else { ESC })
+        .collect();
+    vec![("escape_free", plain), ("escape_heavy", heavy)]
+}
+
+fn bench_crc(c: &mut Criterion) {
+    let data = vec![0x55; PAYLOAD_LEN];
+    let mut group = c.benchmark_group("crc");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("accum_bytes", |b| {
+        b.iter(|| Crc::new().accum_bytes(black_box(&data)))
+    });
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    let mut buf = vec![0u8; encoded_len_upper_bound(PAYLOAD_LEN)];
+    for (name, payload) in payloads() {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("write_packet_data", name),
+            &payload,
+            |b, payload| {
+                let mut writer: Vec<u8> = Vec::with_capacity(buf.len());
+                b.iter(|| {
+                    writer.clear();
+                    writer.write_packet_data(0x00, payload);
+                    black_box(writer.len())
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("encode_packet", name),
+            &payload,
+            |b, payload| b.iter(|| encode_packet(0x00, payload, &mut buf).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, payload) in payloads() {
+        let mut frame = Vec::new();
+        frame.write_packet_data(0x00, &payload);
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(BenchmarkId::new("parse_bytes", name), &frame, |b, frame| {
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(PAYLOAD_LEN + CRC_LEN);
+            b.iter(|| {
+                let (_, result) = parser.parse_bytes(frame, &mut rx_data);
+                assert_eq!(result, RawParseResult::RawPacketReceived(0x00));
+            })
+        });
+    }
+    group.finish();
+}
+
+// Control frames have no payload, so these are measured in frames rather
+// than bytes.
+fn bench_control(c: &mut Criterion) {
+    let mut group = c.benchmark_group("control");
+    group.throughput(Throughput::Elements(1));
+    let mut buf = [0u8; 16];
+    group.bench_function("encode_syn0", |b| {
+        b.iter(|| encode_packet(black_box(0xc0), &[], &mut buf).unwrap())
+    });
+    let frame = [SOF, 0xc0, 0x74, 0x36, SOF];
+    group.bench_function("parse_syn0", |b| {
+        let mut parser = RawPacketParser::new();
+        let mut rx_data = VecPacketBuffer::new(16);
+        b.iter(|| parser.parse_bytes(black_box(&frame), &mut rx_data))
+    });
+    group.finish();
+}
+
+// Sends a user packet from one EndPoint and parses it with another.
+fn bench_round_trip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("round_trip");
+    for (name, payload) in payloads() {
+        let mut storage1 = VecStorage::new(PAYLOAD_LEN, 8);
+        let mut storage2 = VecStorage::new(PAYLOAD_LEN, 8);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        // Handshake.
+        ep1.connect(&mut storage1);
+        while !(ep1.is_connected() && ep2.is_connected()) {
+            for byte in storage1.take_tx_data() {
+                ep2.parse_byte(byte, &mut storage2);
+            }
+            for byte in storage2.take_tx_data() {
+                ep1.parse_byte(byte, &mut storage1);
+            }
+        }
+
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("endpoint", name),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    ep1.write_packet(payload, &mut storage1);
+                    let mut received = 0;
+                    for byte in storage1.take_tx_data() {
+                        if ep2.parse_byte(byte, &mut storage2) == ParseResult::UserPacket {
+                            received += 1;
+                        }
+                    }
+                    assert_eq!(received, 1);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_crc,
+    bench_encode,
+    bench_decode,
+    bench_control,
+    bench_round_trip
+);
+criterion_main!(benches);
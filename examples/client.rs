@@ -25,7 +25,7 @@ struct Opt {
 fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
     println!("Connected to: {}", stream.peer_addr().unwrap());
     let mut buf = [0u8; 4096];
-    stream.write(b"Hello World")?;
+    stream.write_all(b"Hello World")?;
 
     let bytes_read = stream.read(&mut buf)?;
     if bytes_read == 0 {
@@ -42,6 +42,10 @@ fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
 fn main() {
     let opt = Opt::from_args();
 
+    if opt.debug {
+        simple_logger::init().unwrap();
+    }
+
     if opt.verbose {
         println!("{:#?}", opt);
     }
@@ -35,7 +35,7 @@ fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
         }
         println!("Read: {:?}", (&buf[0..bytes_read]).hex_dump());
 
-        stream.write(&buf[0..bytes_read])?;
+        stream.write_all(&buf[0..bytes_read])?;
     }
     Ok(())
 }
@@ -43,6 +43,10 @@ fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
 fn main() {
     let opt = Opt::from_args();
 
+    if opt.debug {
+        simple_logger::init().unwrap();
+    }
+
     if opt.verbose {
         println!("{:#?}", opt);
     }
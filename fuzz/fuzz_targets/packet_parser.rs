@@ -0,0 +1,29 @@
+// Feeds arbitrary bytes into the PacketParser, checking that it never
+// panics, never overflows the PacketBuffer, and only reports sequence
+// numbers which fit in the header.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serial_framing_protocol::packet::{PacketParser, PacketType, PacketTypeResult, SEQ_MASK};
+use serial_framing_protocol::traits::PacketBuffer;
+use serial_framing_protocol::vecstorage::VecPacketBuffer;
+
+const CAPACITY: usize = 34;
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = PacketParser::new();
+    let mut rx_data = VecPacketBuffer::new(CAPACITY);
+    for byte in data.iter() {
+        let result = parser.parse_byte(*byte, &mut rx_data);
+        assert!(rx_data.len() <= CAPACITY);
+        match result {
+            PacketTypeResult::PacketReceived(PacketType::USR { seq })
+            | PacketTypeResult::PacketReceived(PacketType::RTX { seq })
+            | PacketTypeResult::PacketReceived(PacketType::NAK { seq }) => {
+                assert_eq!(seq & !SEQ_MASK, 0);
+            }
+            _ => {}
+        }
+    }
+});
@@ -0,0 +1,45 @@
+// Feeds arbitrary bytes into the RawPacketParser. The first byte selects
+// the capacity of the PacketBuffer, so that small buffers get exercised.
+//
+// Checks that the buffer never overflows, and that the bulk parse_bytes
+// path produces the same results as parsing a byte at a time.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serial_framing_protocol::rawpacket::{RawPacketParser, RawParseResult};
+use serial_framing_protocol::traits::PacketBuffer;
+use serial_framing_protocol::vecstorage::VecPacketBuffer;
+
+fuzz_target!(|data: &[u8]| {
+    let (capacity, bytes) = match data.split_first() {
+        Some((first, rest)) => ((*first % 64) as usize, rest),
+        None => return,
+    };
+
+    let mut parser = RawPacketParser::new();
+    let mut rx_data = VecPacketBuffer::new(capacity);
+    let mut expected = Vec::new();
+    for byte in bytes.iter() {
+        let result = parser.parse_byte(*byte, &mut rx_data);
+        assert!(rx_data.len() <= capacity);
+        if result != RawParseResult::MoreDataNeeded {
+            expected.push((result, rx_data.data().to_vec()));
+        }
+    }
+
+    let mut parser = RawPacketParser::new();
+    let mut rx_data = VecPacketBuffer::new(capacity);
+    let mut results = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let (consumed, result) = parser.parse_bytes(&bytes[idx..], &mut rx_data);
+        assert!(consumed > 0);
+        assert!(rx_data.len() <= capacity);
+        idx += consumed;
+        if result != RawParseResult::MoreDataNeeded {
+            results.push((result, rx_data.data().to_vec()));
+        }
+    }
+    assert_eq!(results, expected);
+});
@@ -0,0 +1,350 @@
+use embedded_io_async::{Read, Write};
+
+use crate::encode::{encoded_len_upper_bound, SliceWriter};
+use crate::traits::{PacketBuffer, PacketQueue, PacketWriter, Storage};
+use crate::{EndPoint, ParseResult};
+
+#[derive(Debug, PartialEq)]
+pub enum AsyncLinkError<E> {
+    /// The transport reported an error.
+    Io(E),
+
+    /// The transport reached end of file.
+    Eof,
+
+    /// The link hasn't been connected yet.
+    NotConnected,
+
+    /// The encoded packet wouldn't fit in the transmit buffer.
+    PacketTooLarge,
+
+    /// The frames to be sent (e.g. a retransmission of the history)
+    /// overflowed the transmit buffer, so none of them were sent. The
+    /// EndPoint treats them as lost on the wire.
+    BufferOverflow,
+}
+
+impl<E> From<E> for AsyncLinkError<E> {
+    fn from(err: E) -> Self {
+        AsyncLinkError::Io(err)
+    }
+}
+
+// Uses the rx_buf and tx_queue from the caller's storage, but directs the
+// transmitted bytes into a SliceWriter so that they can be sent
+// asynchronously.
+struct LinkStorage<'s, 'w> {
+    inner: &'s mut dyn Storage,
+    writer: &'s mut SliceWriter<'w>,
+}
+
+impl<'s, 'w> Storage for LinkStorage<'s, 'w> {
+    fn rx_buf(&mut self) -> &mut dyn PacketBuffer {
+        self.inner.rx_buf()
+    }
+
+    fn tx_writer(&mut self) -> &mut dyn PacketWriter {
+        self.writer
+    }
+
+    fn tx_queue(&mut self) -> &mut dyn PacketQueue {
+        self.inner.tx_queue()
+    }
+}
+
+/// An async link which runs an EndPoint over an embedded-io-async
+/// transport, without requiring an allocator.
+///
+/// The rx_buf and tx_queue from `storage` are used as usual, but its
+/// tx_writer is not. Instead, frames are encoded into `tx_buf` and then
+/// written to the transport, waiting for the write to complete. `tx_buf`
+/// should be large enough to hold the retransmission of the entire history,
+/// since that is triggered by a single received NAK. If it isn't, the
+/// call which received the NAK returns BufferOverflow.
+///
+/// Received bytes are read into `rx_chunk`, so its size determines the
+/// largest read (e.g. DMA transfer) that will be requested from the
+/// transport.
+///
+/// There is no timer in this layer. If the remote side may not be running
+/// yet, wrap `connect` in a timeout (e.g. `embassy_time::with_timeout`) and
+/// call it again when it expires.
+pub struct AsyncLink<'a, T> {
+    transport: T,
+    endpoint: EndPoint,
+    storage: &'a mut dyn Storage,
+    tx_buf: &'a mut [u8],
+    rx_chunk: &'a mut [u8],
+    rx_idx: usize,
+    rx_len: usize,
+}
+
+impl<'a, T: Read + Write> AsyncLink<'a, T> {
+    pub fn new(
+        transport: T,
+        storage: &'a mut dyn Storage,
+        tx_buf: &'a mut [u8],
+        rx_chunk: &'a mut [u8],
+    ) -> Self {
+        Self {
+            transport,
+            endpoint: EndPoint::new(),
+            storage,
+            tx_buf,
+            rx_chunk,
+            rx_idx: 0,
+            rx_len: 0,
+        }
+    }
+
+    pub fn endpoint(&self) -> &EndPoint {
+        &self.endpoint
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.endpoint.is_connected()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    // Writes the bytes produced by `f` to the transport, and waits for the
+    // transmission to complete.
+    async fn transmit<R>(
+        &mut self,
+        f: impl FnOnce(&mut EndPoint, &mut dyn Storage) -> R,
+    ) -> Result<R, AsyncLinkError<T::Error>> {
+        let (result, tx_len) = self.stage(f)?;
+        self.write_tx(tx_len).await?;
+        Ok(result)
+    }
+
+    // Runs `f`, collecting the bytes it produces in `tx_buf`. Returns the
+    // result of `f` along with the number of bytes to write.
+    fn stage<R>(
+        &mut self,
+        f: impl FnOnce(&mut EndPoint, &mut dyn Storage) -> R,
+    ) -> Result<(R, usize), AsyncLinkError<T::Error>> {
+        let mut writer = SliceWriter::new(self.tx_buf);
+        let result = f(
+            &mut self.endpoint,
+            &mut LinkStorage {
+                inner: self.storage,
+                writer: &mut writer,
+            },
+        );
+        if writer.overflowed() {
+            return Err(AsyncLinkError::BufferOverflow);
+        }
+        Ok((result, writer.data().len()))
+    }
+
+    async fn write_tx(&mut self, tx_len: usize) -> Result<(), AsyncLinkError<T::Error>> {
+        if tx_len > 0 {
+            self.transport.write_all(&self.tx_buf[..tx_len]).await?;
+            self.transport.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Starts the handshake, and waits for the link to be connected.
+    pub async fn connect(&mut self) -> Result<(), AsyncLinkError<T::Error>> {
+        self.transmit(|ep, storage| ep.connect(storage)).await?;
+        while !self.is_connected() {
+            self.receive_one().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as a user packet.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), AsyncLinkError<T::Error>> {
+        if !self.is_connected() {
+            return Err(AsyncLinkError::NotConnected);
+        }
+        if encoded_len_upper_bound(data.len()) > self.tx_buf.len() {
+            return Err(AsyncLinkError::PacketTooLarge);
+        }
+        self.transmit(|ep, storage| ep.write_packet(data, storage))
+            .await
+    }
+
+    /// Waits for a user packet to arrive, and returns its contents. The
+    /// packet remains valid until the next call which receives data.
+    pub async fn recv(&mut self) -> Result<&[u8], AsyncLinkError<T::Error>> {
+        while self.receive_one().await? != ParseResult::UserPacket {}
+        Ok(self.storage.rx_buf().data())
+    }
+
+    // Parses received bytes until something interesting happens (e.g. a
+    // complete frame arrives), reading another chunk from the transport if
+    // needed.
+    async fn receive_one(&mut self) -> Result<ParseResult, AsyncLinkError<T::Error>> {
+        if self.rx_idx >= self.rx_len {
+            let bytes_read = self.transport.read(self.rx_chunk).await?;
+            if bytes_read == 0 {
+                return Err(AsyncLinkError::Eof);
+            }
+            self.rx_idx = 0;
+            self.rx_len = bytes_read;
+        }
+        while self.rx_idx < self.rx_len {
+            // The chunk is moved out while the EndPoint parses it, and put
+            // back before anything is awaited.
+            let rx_chunk = core::mem::take(&mut self.rx_chunk);
+            let bytes = &rx_chunk[self.rx_idx..self.rx_len];
+            let staged = self.stage(|ep, storage| ep.parse_bytes(bytes, storage));
+            self.rx_chunk = rx_chunk;
+            let ((consumed, parse_result), tx_len) = staged?;
+            self.rx_idx += consumed;
+            self.write_tx(tx_len).await?;
+            if parse_result != ParseResult::MoreDataNeeded {
+                return Ok(parse_result);
+            }
+        }
+        Ok(ParseResult::MoreDataNeeded)
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::SOF;
+    use crate::vecstorage::VecStorage;
+    use core::convert::Infallible;
+    use futures::executor::block_on;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    // A fake transport which returns the queued bytes, at most `chunk` at a
+    // time, and records the bytes written.
+    struct FakeTransport {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+        reads: usize,
+    }
+
+    impl FakeTransport {
+        fn new(rx: &[u8]) -> Self {
+            Self {
+                rx: rx.iter().copied().collect(),
+                tx: Vec::new(),
+                reads: 0,
+            }
+        }
+    }
+
+    impl embedded_io_async::ErrorType for FakeTransport {
+        type Error = Infallible;
+    }
+
+    impl Read for FakeTransport {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            self.reads += 1;
+            let mut len = 0;
+            while len < buf.len() {
+                match self.rx.pop_front() {
+                    Some(byte) => buf[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeTransport {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_link() {
+        setup_log();
+        let mut rx = Vec::new();
+        // Remote SYN0, SYN2 and 2 user packets, all in one burst.
+        rx.extend_from_slice(&[SOF, 0xc0, 0x74, 0x36, SOF]);
+        rx.extend_from_slice(&[SOF, 0xc2, 0x66, 0x15, SOF]);
+        rx.extend_from_slice(&[SOF, 0x00, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67]);
+        rx.extend_from_slice(&[0xc5, 0x5c, SOF]);
+        rx.extend_from_slice(&[SOF, 0x01, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67]);
+        rx.extend_from_slice(&[0x7a, 0xdd, SOF]);
+
+        let mut storage = VecStorage::new(64, 4);
+        let mut tx_buf = [0u8; 64];
+        let mut rx_chunk = [0u8; 16];
+        let mut link = AsyncLink::new(
+            FakeTransport::new(&rx),
+            &mut storage,
+            &mut tx_buf,
+            &mut rx_chunk,
+        );
+
+        block_on(async {
+            assert_eq!(link.send(b"Early").await, Err(AsyncLinkError::NotConnected));
+            link.connect().await.unwrap();
+            assert_eq!(link.recv().await.unwrap(), b"Testing");
+            assert_eq!(link.recv().await.unwrap(), b"Testing");
+            link.send(b"Testing").await.unwrap();
+            assert_eq!(
+                link.send(&[0; 30]).await,
+                Err(AsyncLinkError::PacketTooLarge)
+            );
+            assert_eq!(link.recv().await, Err(AsyncLinkError::Eof));
+        });
+
+        let transport = link.into_inner();
+        // 34 bytes were read in 16 byte chunks, followed by the EOF.
+        assert_eq!(transport.reads, 4);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[SOF, 0xc0, 0x74, 0x36, SOF]);
+        expected.extend_from_slice(&[SOF, 0xc1, 0xfd, 0x27, SOF]);
+        expected.extend_from_slice(&[SOF, 0x00, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67]);
+        expected.extend_from_slice(&[0xc5, 0x5c, SOF]);
+        assert_eq!(transport.tx, expected);
+    }
+
+    #[test]
+    fn test_buffer_overflow() {
+        setup_log();
+        let mut rx = Vec::new();
+        rx.extend_from_slice(&[SOF, 0xc0, 0x74, 0x36, SOF]);
+        rx.extend_from_slice(&[SOF, 0xc2, 0x66, 0x15, SOF]);
+        // A NAK asking for everything from seq 0 to be retransmitted.
+        rx.extend_from_slice(&[SOF, 0x80, 0x70, 0x74, SOF]);
+
+        // Each packet fits, but the retransmission of all 3 doesn't.
+        let mut storage = VecStorage::new(64, 4);
+        let mut tx_buf = [0u8; 24];
+        // Read a frame at a time, so the NAK isn't seen while connecting.
+        let mut rx_chunk = [0u8; 5];
+        let mut link = AsyncLink::new(
+            FakeTransport::new(&rx),
+            &mut storage,
+            &mut tx_buf,
+            &mut rx_chunk,
+        );
+
+        block_on(async {
+            link.connect().await.unwrap();
+            for _ in 0..3 {
+                link.send(b"Testing").await.unwrap();
+            }
+            assert_eq!(link.recv().await, Err(AsyncLinkError::BufferOverflow));
+        });
+
+        // Only the handshake and the 3 original packets were written.
+        let transport = link.into_inner();
+        assert_eq!(transport.tx.len(), 10 + 3 * 12);
+    }
+}
@@ -0,0 +1,71 @@
+// Helpers shared by the command line tools. Not every tool uses all of them.
+#![allow(dead_code)]
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use pretty_hex::*;
+
+/// The two halves of a byte stream, so that it can be read and written from
+/// different threads.
+pub type Transport = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+fn open_tcp(addr: &str, listen: bool) -> io::Result<Transport> {
+    let stream = if listen {
+        let (stream, peer) = TcpListener::bind(addr)?.accept()?;
+        eprintln!("Connection from {}", peer);
+        stream
+    } else {
+        TcpStream::connect(addr)?
+    };
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+#[cfg(unix)]
+fn open_unix(path: &str, listen: bool) -> io::Result<Transport> {
+    let stream = if listen {
+        UnixListener::bind(path)?.accept()?.0
+    } else {
+        UnixStream::connect(path)?
+    };
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+#[cfg(not(unix))]
+fn open_unix(_path: &str, _listen: bool) -> io::Result<Transport> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets aren't supported on this platform",
+    ))
+}
+
+/// Opens tcp:HOST:PORT, unix:PATH, or the path of a serial port or PTY. With
+/// `listen`, waits for a single connection on the tcp: or unix: address.
+pub fn open(target: &str, listen: bool) -> io::Result<Transport> {
+    if let Some(addr) = target.strip_prefix("tcp:") {
+        return open_tcp(addr, listen);
+    }
+    if let Some(path) = target.strip_prefix("unix:") {
+        return open_unix(path, listen);
+    }
+    if listen {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--listen needs a tcp: or unix: address",
+        ));
+    }
+    let file = OpenOptions::new().read(true).write(true).open(target)?;
+    Ok((Box::new(file.try_clone()?), Box::new(file)))
+}
+
+// Formats a payload as a hex dump, indented to go under a frame summary.
+// The "Length:" line is skipped, since the length is in the summary.
+pub fn dump_payload(payload: &[u8]) -> String {
+    let dump = pretty_hex(&payload);
+    let rows = dump.split_once('\n').map_or("", |(_, rows)| rows);
+    let lines: Vec<String> = rows.lines().map(|line| format!("    {}", line)).collect();
+    lines.join("\n")
+}
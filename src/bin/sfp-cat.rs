@@ -0,0 +1,342 @@
+// Bridges stdin/stdout to an SFP link. Each line read from stdin is sent as
+// a user packet, and each user packet received is written to stdout.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serial_framing_protocol::stream::SfpStream;
+
+use structopt::StructOpt;
+
+mod common;
+
+const HISTORY_LEN: usize = 8;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sfp-cat")]
+struct Opt {
+    /// Send and print packets as hex bytes (e.g. "48 69") rather than raw data
+    #[structopt(short = "x", long)]
+    hex: bool,
+
+    /// Wait for a connection on the tcp: or unix: address rather than connecting to it
+    #[structopt(short, long)]
+    listen: bool,
+
+    /// Largest user packet which can be sent or received
+    #[structopt(long, default_value = "256")]
+    packet_size: usize,
+
+    /// Milliseconds between handshake attempts while not connected, and
+    /// between retransmissions of packets which haven't been answered
+    #[structopt(long, default_value = "500")]
+    retry_ms: u64,
+
+    /// Quit this many seconds after stdin is closed, rather than waiting for
+    /// the link to be closed
+    #[structopt(short, long)]
+    quit: Option<u64>,
+
+    /// Turn on debugging
+    #[structopt(short, long)]
+    debug: bool,
+
+    /// tcp:HOST:PORT, unix:PATH, or the path of a serial port or PTY (which
+    /// should already be in raw mode)
+    target: String,
+}
+
+enum Event {
+    Line(Vec<u8>),
+    StdinClosed,
+    Received(Vec<u8>),
+    LinkClosed(io::Result<()>),
+}
+
+// Parses whitespace separated hex bytes. Runs of digits without spaces
+// (e.g. "4869") are also accepted.
+fn parse_hex(line: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in line.split_whitespace() {
+        if !word.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("invalid hex bytes '{}'", word));
+        }
+        if word.len() % 2 != 0 {
+            return Err(format!("odd number of hex digits in '{}'", word));
+        }
+        for idx in (0..word.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&word[idx..idx + 2], 16).unwrap());
+        }
+    }
+    Ok(bytes)
+}
+
+fn format_hex(bytes: &[u8]) -> String {
+    let words: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    words.join(" ")
+}
+
+fn spawn_stdin_reader(events: Sender<Event>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            let mut line = Vec::new();
+            match stdin.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                        if line.last() == Some(&b'\r') {
+                            line.pop();
+                        }
+                    }
+                    if events.send(Event::Line(line)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = events.send(Event::StdinClosed);
+    });
+}
+
+fn spawn_link_reader(mut reader: Box<dyn Read + Send>, events: Sender<Event>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let event = match reader.read(&mut buf) {
+                Ok(0) => Event::LinkClosed(Ok(())),
+                Ok(len) => Event::Received(buf[..len].to_vec()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Event::LinkClosed(Err(err)),
+            };
+            let closed = matches!(event, Event::LinkClosed(_));
+            if events.send(event).is_err() || closed {
+                return;
+            }
+        }
+    });
+}
+
+// The transport given to the SfpStream. The bytes read come from the link
+// reader thread by way of the event channel, so that a line from stdin can
+// interrupt a blocked recv. Those events are queued in `stdin`, and the read
+// fails with Interrupted.
+struct Transport {
+    events: Receiver<Event>,
+    stdin: VecDeque<Event>,
+    // Bytes received which didn't fit in the last read.
+    rx: Vec<u8>,
+    writer: Box<dyn Write + Send>,
+    timeout: Duration,
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rx.is_empty() {
+            match self.events.recv_timeout(self.timeout) {
+                Ok(Event::Received(bytes)) => self.rx = bytes,
+                Ok(Event::LinkClosed(result)) => return result.map(|()| 0),
+                Ok(event) => {
+                    self.stdin.push_back(event);
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.rx.len());
+        buf[..len].copy_from_slice(&self.rx[..len]);
+        self.rx.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Errors from the SfpStream which just mean that there's something else to
+// do: the read timed out (and the handshake or retransmission was
+// restarted), or stdin has something for us.
+fn is_wakeup(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+fn print_packet(packet: &[u8], hex: bool) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if hex {
+        writeln!(stdout, "{}", format_hex(packet))?;
+    } else {
+        stdout.write_all(packet)?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()
+}
+
+fn run(opt: &Opt) -> io::Result<()> {
+    let (reader, writer) = common::open(&opt.target, opt.listen)?;
+    let (tx, events) = mpsc::channel();
+    spawn_link_reader(reader, tx.clone());
+    spawn_stdin_reader(tx);
+
+    let retry = Duration::from_millis(opt.retry_ms);
+    let transport = Transport {
+        events,
+        stdin: VecDeque::new(),
+        rx: Vec::new(),
+        writer,
+        timeout: retry,
+    };
+    // The SfpStream restarts the handshake, or retransmits the packets which
+    // haven't been answered, each time a read times out.
+    let mut stream = SfpStream::with_capacity(transport, opt.packet_size, HISTORY_LEN);
+    stream.set_connect_attempts(1);
+    match stream.connect() {
+        Err(err) if !is_wakeup(&err) => return Err(err),
+        _ => {}
+    }
+
+    let mut buf = vec![0u8; opt.packet_size];
+    // Packets waiting for the link to be connected.
+    let mut pending: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        while let Some(event) = stream.get_mut().stdin.pop_front() {
+            match event {
+                Event::Line(line) => {
+                    let packet = if opt.hex {
+                        match parse_hex(&String::from_utf8_lossy(&line)) {
+                            Ok(packet) => packet,
+                            Err(err) => {
+                                eprintln!("sfp-cat: {}", err);
+                                continue;
+                            }
+                        }
+                    } else {
+                        line
+                    };
+                    if packet.len() > opt.packet_size {
+                        eprintln!(
+                            "sfp-cat: packet of {} bytes is larger than --packet-size {}",
+                            packet.len(),
+                            opt.packet_size
+                        );
+                    } else {
+                        pending.push_back(packet);
+                    }
+                }
+                Event::StdinClosed => {
+                    if let Some(secs) = opt.quit {
+                        deadline = Some(Instant::now() + Duration::from_secs(secs));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if stream.is_connected() {
+            while let Some(packet) = pending.pop_front() {
+                stream.send(&packet)?;
+            }
+        }
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            stream.get_mut().timeout = retry.min(deadline - now);
+        }
+        match stream.recv(&mut buf) {
+            Ok(len) => print_packet(&buf[..len], opt.hex)?,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) if is_wakeup(&err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if opt.debug {
+        simple_logger::init().unwrap();
+    }
+
+    if let Err(err) = run(&opt) {
+        eprintln!("sfp-cat: {}: {}", opt.target, err);
+        process::exit(1);
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("48 69"), Ok(vec![0x48, 0x69]));
+        assert_eq!(parse_hex(" 4869  7e\t"), Ok(vec![0x48, 0x69, 0x7e]));
+        assert_eq!(parse_hex(""), Ok(vec![]));
+        assert!(parse_hex("486").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert!(parse_hex("+1").is_err());
+    }
+
+    #[test]
+    fn test_format_hex() {
+        assert_eq!(format_hex(&[0x48, 0x69, 0x7e]), "48 69 7e");
+        assert_eq!(format_hex(&[]), "");
+        assert_eq!(parse_hex(&format_hex(&[0, 0xff])), Ok(vec![0, 0xff]));
+    }
+
+    #[test]
+    fn test_transport() {
+        let (tx, events) = mpsc::channel();
+        let mut transport = Transport {
+            events,
+            stdin: VecDeque::new(),
+            rx: Vec::new(),
+            writer: Box::new(io::sink()),
+            timeout: Duration::from_millis(1),
+        };
+        let mut buf = [0u8; 2];
+
+        // A line from stdin interrupts the read, and bytes which don't fit
+        // are returned by the next read.
+        tx.send(Event::Line(b"Hi".to_vec())).unwrap();
+        tx.send(Event::Received(vec![1, 2, 3])).unwrap();
+        let err = transport.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(matches!(transport.stdin.pop_front(), Some(Event::Line(line)) if line == b"Hi"));
+        assert_eq!(transport.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+
+        let err = transport.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        tx.send(Event::LinkClosed(Ok(()))).unwrap();
+        assert_eq!(transport.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// Decodes a capture of raw SFP bytes (e.g. logged from a UART) and prints
+// each frame found in it, including damaged ones.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use pretty_hex::*;
+use serial_framing_protocol::decode::{Decoder, Frame, FrameStatus};
+use serial_framing_protocol::pcapng::{self, Direction, PcapngWriter};
+
+use structopt::StructOpt;
+
+mod common;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sfp-decode")]
+struct Opt {
+    /// Also print the bytes of each frame as they appeared on the wire
+    #[structopt(short, long)]
+    raw: bool,
+
+    /// Only print the summary line for each frame, without a payload dump
+    #[structopt(short, long)]
+    summary: bool,
+
+    /// Largest payload which will be decoded
+    #[structopt(long, default_value = "65536")]
+    max_payload: usize,
+
+    /// Also write the frames to a pcapng file, which can be opened in
+    /// Wireshark with the dissector from --dissector
+    #[structopt(long)]
+    pcapng: Option<PathBuf>,
+
+    /// Print the Wireshark Lua dissector for --pcapng files and exit
+    #[structopt(long)]
+    dissector: bool,
+
+    /// Capture file to decode (stdin is used if omitted, or if it's "-")
+    input: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Stats {
+    frames: usize,
+    good: usize,
+    crc_errors: usize,
+    aborted: usize,
+    runts: usize,
+    truncated: usize,
+}
+
+impl Stats {
+    fn add(&mut self, frame: &Frame) {
+        self.frames += 1;
+        match frame.status {
+            FrameStatus::Good => self.good += 1,
+            FrameStatus::CrcError(_) => self.crc_errors += 1,
+            FrameStatus::Aborted => self.aborted += 1,
+            FrameStatus::TooSmall => self.runts += 1,
+            FrameStatus::Truncated => self.truncated += 1,
+        }
+    }
+}
+
+// Prints a frame. `secs` is the time since the start of the capture at which
+// the end of the frame was read, which is mostly useful when decoding a live
+// stream from stdin.
+fn print_frame(out: &mut dyn Write, opt: &Opt, secs: f64, frame: &Frame) -> io::Result<()> {
+    writeln!(out, "{:12.6} {:8} {}", secs, frame.offset, frame)?;
+    if opt.raw {
+        writeln!(out, "    raw: {}", simple_hex(&frame.raw))?;
+    }
+    if !opt.summary && !frame.payload.is_empty() {
+        writeln!(out, "{}", common::dump_payload(&frame.payload))?;
+    }
+    Ok(())
+}
+
+fn run(opt: &Opt) -> io::Result<()> {
+    if opt.dissector {
+        return io::stdout().write_all(pcapng::dissector().as_bytes());
+    }
+    let mut input: Box<dyn Read> = match &opt.input {
+        Some(path) if path.as_os_str() != "-" => Box::new(File::open(path)?),
+        _ => Box::new(io::stdin()),
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut pcapng = match &opt.pcapng {
+        Some(path) => Some(PcapngWriter::new(io::BufWriter::new(File::create(path)?))?),
+        None => None,
+    };
+
+    let start = Instant::now();
+    let mut decoder = Decoder::with_capacity(opt.max_payload);
+    let mut stats = Stats::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let secs = start.elapsed().as_secs_f64();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        for frame in decoder.decode(&buf[..len]) {
+            print_frame(&mut out, opt, secs, &frame)?;
+            if let Some(pcapng) = pcapng.as_mut() {
+                pcapng.write_frame(&frame, Direction::Unknown, timestamp)?;
+            }
+            stats.add(&frame);
+        }
+        out.flush()?;
+    }
+    if let Some(frame) = decoder.finish() {
+        print_frame(&mut out, opt, start.elapsed().as_secs_f64(), &frame)?;
+        if let Some(pcapng) = pcapng.as_mut() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            pcapng.write_frame(&frame, Direction::Unknown, timestamp)?;
+        }
+        stats.add(&frame);
+    }
+    if let Some(pcapng) = pcapng.as_mut() {
+        pcapng.flush()?;
+    }
+    writeln!(
+        out,
+        "{} bytes, {} frames: {} good, {} CRC errors, {} aborted, {} runts, {} truncated",
+        decoder.offset(),
+        stats.frames,
+        stats.good,
+        stats.crc_errors,
+        stats.aborted,
+        stats.runts,
+        stats.truncated
+    )
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if let Err(err) = run(&opt) {
+        eprintln!("sfp-decode: {}", err);
+        process::exit(1);
+    }
+}
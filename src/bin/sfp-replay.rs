@@ -0,0 +1,95 @@
+// Replays a session log written by serial_framing_protocol::record::Recorder
+// against a fresh EndPoint, and reports where its behavior differs from the
+// recording.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+use serial_framing_protocol::record::{self, Event};
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sfp-replay")]
+struct Opt {
+    /// Print every recorded event, not just the divergence
+    #[structopt(short, long)]
+    verbose: bool,
+
+    /// Session log to replay
+    log: PathBuf,
+}
+
+fn print_event(out: &mut dyn Write, index: usize, event: Option<&Event>) -> io::Result<()> {
+    match event {
+        Some(event) => writeln!(out, "{:8} {}", index, event),
+        None => writeln!(out, "{:8} (end of log)", index),
+    }
+}
+
+// Returns whether the replay matched the recording.
+fn run(opt: &Opt) -> io::Result<bool> {
+    let log = fs::read(&opt.log)?;
+    let replay = record::replay(&log)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+    let divergence = replay.divergence();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(
+        out,
+        "packet size {}, history length {}, receive buffer {}, {} events",
+        replay.header.packet_size,
+        replay.header.history_len,
+        replay.header.rx_capacity,
+        replay.recorded.len()
+    )?;
+    let end = divergence
+        .as_ref()
+        .map_or(replay.recorded.len(), |divergence| divergence.index);
+    if opt.verbose {
+        for (index, event) in replay.recorded[..end].iter().enumerate() {
+            print_event(&mut out, index, Some(event))?;
+        }
+    }
+    match divergence {
+        Some(divergence) => {
+            match divergence.tick {
+                Some(tick) => writeln!(out, "diverged after tick {}", tick)?,
+                None => writeln!(out, "diverged before the first tick")?,
+            }
+            writeln!(out, "recorded:")?;
+            print_event(
+                &mut out,
+                divergence.index,
+                replay.recorded.get(divergence.index),
+            )?;
+            writeln!(out, "replayed:")?;
+            print_event(
+                &mut out,
+                divergence.index,
+                replay.replayed.get(divergence.index),
+            )?;
+            Ok(false)
+        }
+        None => {
+            writeln!(out, "replay matches the recording")?;
+            Ok(true)
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    match run(&opt) {
+        Ok(true) => {}
+        Ok(false) => process::exit(2),
+        Err(err) => {
+            eprintln!("sfp-replay: {}: {}", opt.log.display(), err);
+            process::exit(1);
+        }
+    }
+}
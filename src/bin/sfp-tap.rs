@@ -0,0 +1,378 @@
+// Sits between two SFP peers, forwarding the bytes in each direction and
+// printing the frames seen in both directions as a single timeline. Commands
+// read from stdin can drop or corrupt frames as they pass through.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serial_framing_protocol::decode::{Decoder, Frame};
+use serial_framing_protocol::pcapng::{self, PcapngWriter};
+use serial_framing_protocol::traits::{ESC, SOF};
+
+use structopt::StructOpt;
+
+mod common;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sfp-tap")]
+struct Opt {
+    /// Wait for a connection on the tcp: or unix: address of A rather than
+    /// connecting to it
+    #[structopt(short, long)]
+    listen: bool,
+
+    /// Also print a hex dump of each payload
+    #[structopt(short, long)]
+    dump: bool,
+
+    /// Also write the frames from both directions to a pcapng file
+    #[structopt(long)]
+    pcapng: Option<PathBuf>,
+
+    /// tcp:HOST:PORT, unix:PATH, or the path of a serial port or PTY
+    a: String,
+
+    /// tcp:HOST:PORT, unix:PATH, or the path of a serial port or PTY
+    b: String,
+}
+
+const HELP: &str = "\
+Commands:
+    drop a|b [COUNT]     drop the next COUNT frames sent by a or b
+    corrupt a|b [COUNT]  flip a bit in each of the next COUNT frames sent by a or b
+    help                 show this message";
+
+const NAMES: [&str; 2] = ["A>B", "B>A"];
+const DIRECTIONS: [pcapng::Direction; 2] = [pcapng::Direction::AToB, pcapng::Direction::BToA];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fate {
+    Forwarded,
+    Dropped,
+    Corrupted,
+}
+
+// Forwards the bytes sent in one direction, dropping or corrupting whole
+// frames on request, and decodes the frames as they were sent.
+#[derive(Default)]
+struct Direction {
+    decoder: Decoder,
+    drops: usize,
+    corrupts: usize,
+    in_frame: bool,
+    dropping: bool,
+    corrupted: bool,
+    // Whether the previous byte of the frame was an ESC.
+    escaped: bool,
+}
+
+impl Direction {
+    // Returns the bytes to forward, and the frames which were completed.
+    fn process(&mut self, bytes: &[u8]) -> (Vec<u8>, Vec<(Frame, Fate)>) {
+        let mut forward = Vec::with_capacity(bytes.len());
+        let mut frames = Vec::new();
+        for byte in bytes.iter() {
+            let mut byte = *byte;
+            if !self.in_frame && byte != SOF {
+                self.in_frame = true;
+                self.dropping = self.drops > 0;
+                if self.dropping {
+                    self.drops -= 1;
+                }
+                self.corrupted = false;
+                self.escaped = false;
+            }
+            let fate = if self.dropping {
+                Fate::Dropped
+            } else if self.corrupted {
+                Fate::Corrupted
+            } else {
+                Fate::Forwarded
+            };
+            if let Some(frame) = self.decoder.decode(&[byte]).pop() {
+                frames.push((frame, fate));
+            }
+            if !self.in_frame {
+                // SOFs between frames are always forwarded.
+                forward.push(byte);
+                continue;
+            }
+            if byte == SOF {
+                self.in_frame = false;
+            }
+            if self.dropping {
+                continue;
+            }
+            if self.in_frame && self.corrupts > 0 && !self.corrupted {
+                byte = self.corrupt(byte);
+            }
+            self.escaped = byte == ESC;
+            forward.push(byte);
+        }
+        (forward, frames)
+    }
+
+    // Flips the low bit of the byte, unless that would change the framing
+    // or escaping (which would damage more than one byte of the frame).
+    fn corrupt(&mut self, byte: u8) -> u8 {
+        let flipped = byte ^ 0x01;
+        let special = |byte| byte == SOF || byte == ESC;
+        if self.escaped || special(byte) || special(flipped) {
+            return byte;
+        }
+        self.corrupts -= 1;
+        self.corrupted = true;
+        flipped
+    }
+}
+
+enum Event {
+    Data(usize, Vec<u8>),
+    Closed(usize, io::Result<()>),
+    Command(String),
+}
+
+fn spawn_reader(side: usize, mut reader: Box<dyn Read + Send>, events: Sender<Event>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let event = match reader.read(&mut buf) {
+                Ok(0) => Event::Closed(side, Ok(())),
+                Ok(len) => Event::Data(side, buf[..len].to_vec()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Event::Closed(side, Err(err)),
+            };
+            let closed = matches!(event, Event::Closed(..));
+            if events.send(event).is_err() || closed {
+                return;
+            }
+        }
+    });
+}
+
+fn spawn_command_reader(events: Sender<Event>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if events.send(Event::Command(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+fn run_command(line: &str, directions: &mut [Direction; 2]) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (cmd, args) = match words.split_first() {
+        Some((cmd, args)) => (*cmd, args),
+        None => return Ok(String::new()),
+    };
+    if cmd == "help" {
+        return Ok(HELP.to_string());
+    }
+    let side = match args.first().map(|side| side.to_ascii_lowercase()) {
+        Some(side) if side == "a" => 0,
+        Some(side) if side == "b" => 1,
+        _ => return Err(format!("{}: expected a or b\n{}", line, HELP)),
+    };
+    let count = match args.get(1) {
+        Some(count) => count
+            .parse()
+            .map_err(|_| format!("{}: invalid count", line))?,
+        None => 1,
+    };
+    let direction = &mut directions[side];
+    match cmd {
+        "drop" => {
+            direction.drops += count;
+            Ok(format!(
+                "{}: dropping the next {} frames",
+                NAMES[side], direction.drops
+            ))
+        }
+        "corrupt" => {
+            direction.corrupts += count;
+            Ok(format!(
+                "{}: corrupting the next {} frames",
+                NAMES[side], direction.corrupts
+            ))
+        }
+        _ => Err(format!("{}: unknown command\n{}", line, HELP)),
+    }
+}
+
+fn print_frame(
+    out: &mut dyn Write,
+    opt: &Opt,
+    secs: f64,
+    side: usize,
+    frame: &Frame,
+    fate: Fate,
+) -> io::Result<()> {
+    write!(
+        out,
+        "{:12.6} {} {:8} {}",
+        secs, NAMES[side], frame.offset, frame
+    )?;
+    match fate {
+        Fate::Forwarded => writeln!(out)?,
+        Fate::Dropped => writeln!(out, " [dropped]")?,
+        Fate::Corrupted => writeln!(out, " [corrupted]")?,
+    }
+    if opt.dump && !frame.payload.is_empty() {
+        writeln!(out, "{}", common::dump_payload(&frame.payload))?;
+    }
+    Ok(())
+}
+
+fn run(opt: &Opt) -> io::Result<()> {
+    let (reader_a, writer_a) = common::open(&opt.a, opt.listen)?;
+    let (reader_b, writer_b) = common::open(&opt.b, false)?;
+    let mut writers = [writer_b, writer_a];
+    let (tx, events) = mpsc::channel();
+    spawn_reader(0, reader_a, tx.clone());
+    spawn_reader(1, reader_b, tx.clone());
+    spawn_command_reader(tx);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut pcapng = match &opt.pcapng {
+        Some(path) => Some(PcapngWriter::new(BufWriter::new(File::create(path)?))?),
+        None => None,
+    };
+    let start = Instant::now();
+    let mut directions = [Direction::default(), Direction::default()];
+    for event in events.iter() {
+        match event {
+            Event::Data(side, bytes) => {
+                let (forward, frames) = directions[side].process(&bytes);
+                // writers[side] goes to the other side.
+                writers[side].write_all(&forward)?;
+                writers[side].flush()?;
+                let secs = start.elapsed().as_secs_f64();
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                for (frame, fate) in frames.iter() {
+                    print_frame(&mut out, opt, secs, side, frame, *fate)?;
+                    if let Some(pcapng) = pcapng.as_mut() {
+                        pcapng.write_frame(frame, DIRECTIONS[side], timestamp)?;
+                    }
+                }
+                out.flush()?;
+                if let Some(pcapng) = pcapng.as_mut() {
+                    pcapng.flush()?;
+                }
+            }
+            Event::Closed(side, result) => {
+                let name = if side == 0 { &opt.a } else { &opt.b };
+                eprintln!("sfp-tap: {} closed", name);
+                return result;
+            }
+            Event::Command(line) => match run_command(&line, &mut directions) {
+                Ok(reply) if reply.is_empty() => {}
+                Ok(reply) => eprintln!("{}", reply),
+                Err(err) => eprintln!("sfp-tap: {}", err),
+            },
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if let Err(err) = run(&opt) {
+        eprintln!("sfp-tap: {}", err);
+        process::exit(1);
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_framing_protocol::decode::FrameStatus;
+    use serial_framing_protocol::traits::PacketWriter;
+
+    fn frame(header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_packet_data(header, payload);
+        bytes
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut stream = frame(0xc0, &[]);
+        stream.extend(frame(0x00, b"\x7eHi"));
+        let mut direction = Direction::default();
+        let mut forwarded = Vec::new();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(2) {
+            let (forward, done) = direction.process(chunk);
+            forwarded.extend(forward);
+            frames.extend(done);
+        }
+        assert_eq!(forwarded, stream);
+        let summary: Vec<String> = frames
+            .iter()
+            .map(|(frame, fate)| format!("{} {:?}", frame, fate))
+            .collect();
+        assert_eq!(summary, vec!["SYN0 Forwarded", "USR seq=0 len=3 Forwarded"]);
+    }
+
+    #[test]
+    fn test_drop_and_corrupt() {
+        let mut directions = [Direction::default(), Direction::default()];
+        assert!(run_command("drop a", &mut directions).is_ok());
+        assert!(run_command("corrupt A 2", &mut directions).is_ok());
+        assert!(run_command("drop c", &mut directions).is_err());
+        assert!(run_command("corrupt b x", &mut directions).is_err());
+        assert!(run_command("frob a", &mut directions).is_err());
+        assert_eq!(run_command("", &mut directions), Ok(String::new()));
+
+        let one = frame(0x00, b"One");
+        // The header needs escaping, so the first byte which can be
+        // corrupted is the "A".
+        let two = frame(0x7d, b"\x7eA");
+        let three = frame(0x02, b"Three");
+        let mut stream = one.clone();
+        stream.extend(&two);
+        stream.extend(&three);
+        let (forward, frames) = directions[0].process(&stream);
+
+        let fates: Vec<Fate> = frames.iter().map(|(_, fate)| *fate).collect();
+        assert_eq!(fates, vec![Fate::Dropped, Fate::Corrupted, Fate::Corrupted]);
+        assert!(frames.iter().all(|(frame, _)| frame.is_good()));
+        assert_eq!(directions[0].drops, 0);
+        assert_eq!(directions[0].corrupts, 0);
+
+        // The dropped frame leaves just its opening SOF behind, and the
+        // corrupted frames are the same length but fail their CRC.
+        assert_eq!(forward.len(), 1 + two.len() + three.len());
+        let mut decoder = Decoder::new();
+        let received = decoder.decode(&forward);
+        assert_eq!(received.len(), 2);
+        for frame in received.iter() {
+            assert!(matches!(frame.status, FrameStatus::CrcError(_)));
+        }
+        assert_eq!(received[0].raw.len(), two.len() - 1);
+        assert_eq!(received[0].header, Some(0x7d));
+        assert_eq!(received[0].payload, b"\x7e@");
+    }
+}
@@ -0,0 +1,167 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use log::warn;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::crc::CRC_LEN;
+use crate::encode::encoded_len_upper_bound;
+use crate::rawpacket::{RawPacketParser, RawParseResult};
+use crate::traits::{PacketBuffer, PacketWriter};
+use crate::vecstorage::VecPacketBuffer;
+
+/// Adapts a BytesMut so that packets can be written into it using the
+/// PacketWriter trait.
+struct BytesWriter<'a> {
+    dst: &'a mut BytesMut,
+}
+
+impl<'a> PacketWriter for BytesWriter<'a> {
+    fn write_byte(&mut self, byte: u8) {
+        self.dst.put_u8(byte);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.dst.put_slice(bytes);
+    }
+}
+
+/// A tokio codec which frames raw SFP packets. Each frame is represented as
+/// a `(header, payload)` tuple.
+///
+/// Frames which are aborted, too small, or fail the CRC check are logged
+/// and discarded.
+pub struct SfpCodec {
+    parser: RawPacketParser,
+    rx_buf: VecPacketBuffer,
+}
+
+impl SfpCodec {
+    /// Creates a new codec. Received frames with more than `max_packet_size`
+    /// bytes of payload are discarded.
+    pub fn new(max_packet_size: usize) -> Self {
+        Self {
+            parser: RawPacketParser::new(),
+            rx_buf: VecPacketBuffer::new(max_packet_size + CRC_LEN),
+        }
+    }
+}
+
+impl Decoder for SfpCodec {
+    type Item = (u8, Bytes);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut idx = 0;
+        while idx < src.len() {
+            let (consumed, parse_result) = self.parser.parse_bytes(&src[idx..], &mut self.rx_buf);
+            idx += consumed;
+            match parse_result {
+                RawParseResult::RawPacketReceived(header) => {
+                    let _ = src.split_to(idx);
+                    let data = Bytes::copy_from_slice(self.rx_buf.data());
+                    return Ok(Some((header, data)));
+                }
+                RawParseResult::MoreDataNeeded => {}
+                parse_result => {
+                    warn!("Discarding frame: {:?}", parse_result);
+                }
+            }
+        }
+        src.clear();
+        Ok(None)
+    }
+}
+
+impl<'a> Encoder<(u8, &'a [u8])> for SfpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (u8, &'a [u8]), dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let (header, data) = item;
+        dst.reserve(encoded_len_upper_bound(data.len()));
+        BytesWriter { dst }.write_packet_data(header, data);
+        Ok(())
+    }
+}
+
+impl Encoder<(u8, Bytes)> for SfpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (u8, Bytes), dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode((item.0, &item.1[..]), dst)
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::{ESC, SOF};
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    #[test]
+    fn test_encode() {
+        setup_log();
+        let mut codec = SfpCodec::new(64);
+        let mut dst = BytesMut::new();
+        codec.encode((0xc0, &[][..]), &mut dst).unwrap();
+        codec
+            .encode((0xc0, Bytes::from_static(&[0x11, ESC])), &mut dst)
+            .unwrap();
+        assert_eq!(
+            &dst[..],
+            &[SOF, 0xc0, 0x74, 0x36, SOF, SOF, 0xc0, 0x11, ESC, 0x5d, ESC, 0x5d, 0xe8, SOF]
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        setup_log();
+        let mut codec = SfpCodec::new(4);
+        let mut src = BytesMut::new();
+
+        // Frames split across multiple reads.
+        src.extend_from_slice(&[SOF, 0xc0, 0x11, ESC]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+        src.extend_from_slice(&[0x5d, ESC, 0x5d, 0xe8, SOF, 0xc0, 0x74]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some((0xc0, Bytes::from_static(&[0x11, ESC])))
+        );
+        assert_eq!(&src[..], &[0xc0, 0x74]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&[0x36, SOF]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some((0xc0, Bytes::new())));
+
+        // Bad frames are skipped over.
+        src.extend_from_slice(&[SOF, 0x00, 0x00, 0x00, SOF]);
+        src.extend_from_slice(&[SOF, 0xc0, 0x11, ESC, SOF]);
+        src.extend_from_slice(&[SOF, 0xc0, 1, 2, 3, 4, 5, 6, 7, 8, 9, SOF]);
+        src.extend_from_slice(&[SOF, 0x00, 0x78, 0xf0, SOF]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some((0x00, Bytes::new())));
+    }
+
+    #[tokio::test]
+    async fn test_framed() {
+        setup_log();
+        let (a, b) = tokio::io::duplex(64);
+        let mut framed_a = Framed::new(a, SfpCodec::new(256));
+        let mut framed_b = Framed::new(b, SfpCodec::new(256));
+
+        let data = Bytes::from_static(b"Hello \x7e\x7d World");
+        framed_a.send((0x00, data.clone())).await.unwrap();
+        framed_a.send((0x41, Bytes::new())).await.unwrap();
+        assert_eq!(framed_b.next().await.unwrap().unwrap(), (0x00, data));
+        assert_eq!(
+            framed_b.next().await.unwrap().unwrap(),
+            (0x41, Bytes::new())
+        );
+    }
+}
@@ -5,7 +5,12 @@ pub type CrcAccum = u16;
 const CRC_INIT: CrcAccum = 0xffff;
 pub const CRC_GOOD: CrcAccum = 0xf0b8;
 
-#[derive(Debug)]
+/// Number of CRC bytes at the end of each frame. Receive buffers need this
+/// much room beyond the largest payload, since the CRC is only removed once
+/// the frame is complete.
+pub const CRC_LEN: usize = 2;
+
+#[derive(Clone, Debug)]
 pub struct Crc {
     val: CrcAccum,
 }
@@ -63,6 +68,54 @@ impl Crc {
     }
 }
 
+pub type Crc32Accum = u32;
+
+const CRC32_INIT: Crc32Accum = 0xffff_ffff;
+const CRC32_POLY: Crc32Accum = 0xedb8_8320;
+
+/// CRC-32 (as used by Ethernet, zip, etc). This is used to verify larger
+/// blocks of data than a single packet.
+#[derive(Debug)]
+pub struct Crc32 {
+    val: Crc32Accum,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self { val: CRC32_INIT }
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn accum(&mut self, byte: u8) {
+        self.val ^= byte as Crc32Accum;
+        for _ in 0..8 {
+            let mask = (!(self.val & 1)).wrapping_add(1);
+            self.val = (self.val >> 1) ^ (CRC32_POLY & mask);
+        }
+    }
+
+    pub fn accum_bytes(&mut self, bytes: &[u8]) -> Crc32Accum {
+        for byte in bytes.iter() {
+            self.accum(*byte);
+        }
+        self.crc()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+
+    /// Returns the CRC of the bytes accumulated so far.
+    pub fn crc(&self) -> Crc32Accum {
+        !self.val
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -92,4 +145,14 @@ mod tests {
         assert_eq!(!crc.val, 0x581a);
         assert_eq!(crc.accum_crc(), crate::crc::CRC_GOOD);
     }
+    #[test]
+    fn test_crc32() {
+        use crate::crc::Crc32;
+        let mut crc = Crc32::new();
+        assert_eq!(crc.crc(), 0);
+        assert_eq!(crc.accum_bytes(b"123456789"), 0xcbf4_3926);
+        crc.reset();
+        crc.accum_bytes(b"1234");
+        assert_eq!(crc.accum_bytes(b"56789"), 0xcbf4_3926);
+    }
 }
@@ -0,0 +1,266 @@
+use core::fmt;
+use std::vec::Vec;
+
+use crate::crc::{CrcAccum, CRC_LEN};
+use crate::packet::{FrameType, SeqSyn, FRAME_TYPE_MASK, SEQ_MASK};
+use crate::rawpacket::{RawPacketParser, RawParseResult};
+use crate::traits::{PacketBuffer, ESC, SOF};
+use crate::vecstorage::VecPacketBuffer;
+
+const DEFAULT_MAX_PAYLOAD: usize = 64 * 1024;
+
+/// How a frame found in a byte stream ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameStatus {
+    Good,
+    /// The CRC didn't match. Holds the CRC which was received.
+    CrcError(CrcAccum),
+    /// The frame was aborted by an ESC SOF sequence.
+    Aborted,
+    /// The frame was too short to hold a CRC.
+    TooSmall,
+    /// The stream ended part way through the frame.
+    Truncated,
+}
+
+/// A frame found in a byte stream, whether or not it was valid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Offset of the first byte of the frame (after the opening SOF) within
+    /// the stream.
+    pub offset: usize,
+    /// The bytes of the frame as they appeared in the stream, up to and
+    /// including the closing SOF.
+    pub raw: Vec<u8>,
+    /// The header byte, if the frame got that far.
+    pub header: Option<u8>,
+    /// The unescaped payload, without the CRC.
+    pub payload: Vec<u8>,
+    pub status: FrameStatus,
+}
+
+impl Frame {
+    pub fn frame_type(&self) -> Option<FrameType> {
+        self.header
+            .and_then(|header| FrameType::from_u8(header & FRAME_TYPE_MASK))
+    }
+
+    /// The sequence number, or the SYN code for SYN frames.
+    pub fn seq(&self) -> Option<u8> {
+        self.header.map(|header| header & SEQ_MASK)
+    }
+
+    /// The SYN code, if this is a SYN frame with a known code.
+    pub fn syn(&self) -> Option<SeqSyn> {
+        match self.frame_type() {
+            Some(FrameType::SYN) => self.seq().and_then(SeqSyn::from_u8),
+            _ => None,
+        }
+    }
+
+    pub fn is_good(&self) -> bool {
+        self.status == FrameStatus::Good
+    }
+}
+
+impl fmt::Display for Frame {
+    /// Formats a one line summary of the frame, e.g. "USR seq=3 len=5" or
+    /// "SYN1 CRC error (received 0x1234)".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.frame_type(), self.seq()) {
+            (Some(FrameType::SYN), Some(code)) => match self.syn() {
+                Some(SeqSyn::DIS) => write!(f, "DIS")?,
+                Some(syn) => write!(f, "{:?}", syn)?,
+                None => write!(f, "SYN code={}", code)?,
+            },
+            (Some(frame_type), Some(seq)) => write!(f, "{:?} seq={}", frame_type, seq)?,
+            _ => write!(f, "---")?,
+        }
+        if !self.payload.is_empty() {
+            write!(f, " len={}", self.payload.len())?;
+        }
+        match self.status {
+            FrameStatus::Good => Ok(()),
+            FrameStatus::CrcError(crc) => write!(f, " CRC error (received 0x{:04x})", crc),
+            FrameStatus::Aborted => write!(f, " aborted after {} bytes", self.raw.len()),
+            FrameStatus::TooSmall => write!(f, " runt of {} bytes", self.raw.len()),
+            FrameStatus::Truncated => write!(f, " truncated after {} bytes", self.raw.len()),
+        }
+    }
+}
+
+/// Splits a captured byte stream into frames, reporting the damaged ones
+/// along with the good ones. The stream can be fed in arbitrary chunks.
+pub struct Decoder {
+    parser: RawPacketParser,
+    rx_data: VecPacketBuffer,
+    // Offset of the next byte in the stream.
+    offset: usize,
+    raw: Vec<u8>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_MAX_PAYLOAD)
+    }
+
+    /// Creates a decoder for payloads of up to `max_payload` bytes.
+    pub fn with_capacity(max_payload: usize) -> Self {
+        Self {
+            parser: RawPacketParser::new(),
+            rx_data: VecPacketBuffer::new(max_payload + CRC_LEN),
+            offset: 0,
+            raw: Vec::new(),
+        }
+    }
+
+    /// The number of bytes which have been decoded so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Decodes the next chunk of the stream, returning the frames which were
+    /// completed by it.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for byte in bytes.iter() {
+            self.offset += 1;
+            if self.raw.is_empty() && *byte == SOF {
+                // Opening SOF, or idle SOFs between frames.
+                continue;
+            }
+            self.raw.push(*byte);
+            let status = match self.parser.parse_byte(*byte, &mut self.rx_data) {
+                RawParseResult::RawPacketReceived(_) => FrameStatus::Good,
+                RawParseResult::CrcError(crc) => FrameStatus::CrcError(crc),
+                RawParseResult::AbortedPacket => FrameStatus::Aborted,
+                RawParseResult::PacketTooSmall => FrameStatus::TooSmall,
+                RawParseResult::MoreDataNeeded => continue,
+            };
+            frames.push(self.take_frame(status));
+        }
+        frames
+    }
+
+    /// Marks the end of the stream, returning the partial frame (if any)
+    /// which was cut off.
+    pub fn finish(&mut self) -> Option<Frame> {
+        if self.raw.is_empty() {
+            return None;
+        }
+        let frame = self.take_frame(FrameStatus::Truncated);
+        self.parser = RawPacketParser::new();
+        self.rx_data.reset();
+        Some(frame)
+    }
+
+    fn take_frame(&mut self, status: FrameStatus) -> Frame {
+        let raw = core::mem::take(&mut self.raw);
+        // An abort (ESC SOF) right after the opening SOF has no header.
+        let has_header = match status {
+            FrameStatus::Aborted => raw.len() > 2,
+            FrameStatus::Truncated => raw != [ESC],
+            _ => true,
+        };
+        Frame {
+            offset: self.offset - raw.len(),
+            header: if has_header {
+                Some(self.parser.header())
+            } else {
+                None
+            },
+            payload: self.rx_data.data().to_vec(),
+            raw,
+            status,
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::PacketWriter;
+    use std::string::{String, ToString};
+
+    fn frame(header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_packet_data(header, payload);
+        bytes
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut stream = frame(0xc0, &[]);
+        stream.extend(frame(0x05, b"Hi\x7e"));
+        // A USR frame with a corrupted payload byte.
+        let mut bad = frame(0x06, b"Hello");
+        bad[3] ^= 0x01;
+        stream.extend(&bad);
+        // A runt, and a frame aborted by ESC SOF.
+        stream.extend(&[SOF, 0x81, SOF]);
+        stream.extend(&[SOF, 0x41, 0x42, ESC, SOF]);
+        // An unknown SYN code, followed by a truncated frame.
+        stream.extend(frame(0xc9, &[]));
+        stream.extend(&[SOF, 0x07, 0x41]);
+
+        let mut decoder = Decoder::new();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(3) {
+            frames.extend(decoder.decode(chunk));
+        }
+        frames.extend(decoder.finish());
+        assert_eq!(decoder.offset(), stream.len());
+
+        let summary: Vec<String> = frames.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                "SYN0",
+                "USR seq=5 len=3",
+                "USR seq=6 len=5 CRC error (received 0x742e)",
+                "NAK seq=1 runt of 2 bytes",
+                "RTX seq=1 aborted after 4 bytes",
+                "SYN code=9",
+                "USR seq=7 len=1 truncated after 2 bytes",
+            ]
+        );
+
+        assert_eq!(frames[0].offset, 1);
+        assert_eq!(frames[0].raw, &stream[1..5]);
+        assert_eq!(frames[0].syn(), Some(SeqSyn::SYN0));
+        assert!(frames[0].is_good());
+        assert_eq!(frames[1].payload, b"Hi\x7e");
+        assert_eq!(frames[1].frame_type(), Some(FrameType::USR));
+        assert_eq!(frames[1].seq(), Some(5));
+        assert_eq!(frames[5].syn(), None);
+    }
+
+    #[test]
+    fn test_decode_no_header() {
+        let mut decoder = Decoder::new();
+        let frames = decoder.decode(&[SOF, SOF, ESC, SOF, SOF]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].status, FrameStatus::Aborted);
+        assert_eq!(frames[0].header, None);
+        assert_eq!(frames[0].offset, 2);
+        assert_eq!(frames[0].to_string(), "--- aborted after 2 bytes");
+
+        assert!(decoder.decode(&[ESC]).is_empty());
+        let frame = decoder.finish().unwrap();
+        assert_eq!(frame.status, FrameStatus::Truncated);
+        assert_eq!(frame.header, None);
+        assert_eq!(decoder.finish(), None);
+    }
+}
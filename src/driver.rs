@@ -2,7 +2,8 @@ use generic_array::ArrayLength;
 
 pub trait Driver {
     /// Maximum size of a packet sent or received (doesn't include framing or escape bytes).
-    type PACKET_SIZE: ArrayLength<u8>;
+    #[allow(non_camel_case_types)]
+    type PACKET_SIZE: ArrayLength;
 
     /// Called at the beginning of writing a packet. Allows the driver implementation to implement
     /// buffering.
@@ -0,0 +1,155 @@
+use core::cmp::min;
+
+use crate::crc::CRC_LEN;
+use crate::traits::PacketWriter;
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    /// The encoded frame doesn't fit in the supplied buffer.
+    BufferTooSmall,
+}
+
+/// Returns the largest number of bytes that a frame with `payload_len`
+/// bytes of payload can be encoded into. This is the case where the
+/// header, every payload byte and both CRC bytes need to be escaped.
+pub const fn encoded_len_upper_bound(payload_len: usize) -> usize {
+    // SOF + header + payload + CRC + SOF
+    2 + 2 * (1 + payload_len + CRC_LEN)
+}
+
+/// Encodes a complete frame, consisting of `header` and `payload`, into
+/// `buf`, and returns the length of the encoded frame. This produces the
+/// same bytes as `PacketWriter::write_packet_data`, but allows the entire
+/// frame to be handed to the hardware (e.g. a DMA transfer) in one go.
+///
+/// A buffer of `encoded_len_upper_bound(payload.len())` bytes is always
+/// large enough.
+pub fn encode_packet(header: u8, payload: &[u8], buf: &mut [u8]) -> Result<usize, EncodeError> {
+    let mut writer = SliceWriter::new(buf);
+    writer.write_packet_data(header, payload);
+    if writer.overflowed() {
+        return Err(EncodeError::BufferTooSmall);
+    }
+    Ok(writer.len())
+}
+
+/// A PacketWriter which writes into a caller supplied slice. Bytes which
+/// don't fit are dropped, and the overflow is recorded.
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Returns the bytes which have been written so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if any bytes were dropped because the slice was full.
+    pub fn overflowed(&self) -> bool {
+        self.overflow
+    }
+}
+
+impl<'a> PacketWriter for SliceWriter<'a> {
+    fn write_byte(&mut self, byte: u8) {
+        match self.buf.get_mut(self.len) {
+            Some(dst) => {
+                *dst = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let copy_len = min(bytes.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + copy_len].copy_from_slice(&bytes[..copy_len]);
+        self.len += copy_len;
+        if copy_len < bytes.len() {
+            self.overflow = true;
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::{ESC, SOF};
+    use std::vec::Vec;
+
+    #[test]
+    fn test_encode_packet() {
+        let tests: &[(u8, &[u8])] = &[
+            (0xc0, &[]),
+            (0x00, b"Testing"),
+            (0x41, &[SOF, ESC, 0x00, SOF]),
+            (SOF, &[ESC; 40]),
+        ];
+        for (header, payload) in tests.iter() {
+            let mut expected = Vec::new();
+            expected.write_packet_data(*header, payload);
+
+            let mut buf = [0u8; 128];
+            let len = encode_packet(*header, payload, &mut buf).unwrap();
+            assert_eq!(&buf[..len], &expected[..]);
+            assert!(len <= encoded_len_upper_bound(payload.len()));
+
+            // Every buffer which is too small is detected.
+            for short_len in 0..len {
+                assert_eq!(
+                    encode_packet(*header, payload, &mut buf[..short_len]),
+                    Err(EncodeError::BufferTooSmall)
+                );
+            }
+        }
+        assert_eq!(encode_packet(0xc0, &[], &mut [0u8; 5]), Ok(5));
+    }
+
+    #[test]
+    fn test_upper_bound() {
+        // Only the CRC bytes may be left unescaped here.
+        let payload = [SOF; 8];
+        let mut buf = [0u8; 64];
+        let len = encode_packet(ESC, &payload, &mut buf).unwrap();
+        assert!(len >= 2 + 2 * (1 + payload.len()) + 2);
+        assert!(len <= encoded_len_upper_bound(payload.len()));
+        assert_eq!(encoded_len_upper_bound(0), 8);
+    }
+
+    #[test]
+    fn test_slice_writer() {
+        let mut buf = [0u8; 6];
+        let mut writer = SliceWriter::new(&mut buf);
+        writer.write_packet_data(0xc0, &[]);
+        assert_eq!(writer.data(), &[SOF, 0xc0, 0x74, 0x36, SOF]);
+        assert!(!writer.overflowed());
+        writer.write_packet_data(0xc0, &[]);
+        assert_eq!(writer.len(), 6);
+        assert!(writer.overflowed());
+    }
+}
@@ -0,0 +1,191 @@
+use embedded_hal::serial;
+use log::error;
+
+use crate::traits::{PacketWriter, Storage};
+use crate::{EndPoint, ParseResult};
+
+/// A PacketWriter which writes bytes to an embedded-hal serial port. Each
+/// byte blocks until the port has room for it, and the port is flushed at
+/// the end of each packet.
+///
+/// Since PacketWriter has no way of reporting failures, write errors are
+/// logged and the byte is dropped. The receiver will detect the corrupted
+/// frame and request a retransmission.
+pub struct SerialWriter<S> {
+    serial: S,
+}
+
+impl<S: serial::Write<u8>> SerialWriter<S> {
+    pub fn new(serial: S) -> Self {
+        Self { serial }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.serial
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.serial
+    }
+
+    pub fn into_inner(self) -> S {
+        self.serial
+    }
+}
+
+impl<S: serial::Write<u8>> PacketWriter for SerialWriter<S> {
+    fn write_byte(&mut self, byte: u8) {
+        if nb::block!(self.serial.write(byte)).is_err() {
+            error!("Serial write failed");
+        }
+    }
+
+    fn end_write(&mut self) {
+        if nb::block!(self.serial.flush()).is_err() {
+            error!("Serial flush failed");
+        }
+    }
+}
+
+/// Feeds the bytes which are available from `serial` into `endpoint`.
+///
+/// Returns Ok once a user packet has been received, at which point the
+/// packet can be retrieved from the storage's rx_buf. Returns
+/// `nb::Error::WouldBlock` when no more bytes are available, so
+/// `nb::block!(pump(...))` waits for the next user packet.
+pub fn pump<R: serial::Read<u8>>(
+    serial: &mut R,
+    endpoint: &mut EndPoint,
+    storage: &mut dyn Storage,
+) -> nb::Result<(), R::Error> {
+    loop {
+        let byte = serial.read()?;
+        if endpoint.parse_byte(byte, storage) == ParseResult::UserPacket {
+            return Ok(());
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::{PacketBuffer, PacketQueue, SOF};
+    use crate::vecstorage::{VecPacketBuffer, VecPacketQueue};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    // A fake serial port. Bytes written are recorded, and reads return the
+    // queued bytes, followed by WouldBlock.
+    #[derive(Default)]
+    struct FakeSerial {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl serial::Read<u8> for FakeSerial {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl serial::Write<u8> for FakeSerial {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            self.tx.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    struct SerialStorage {
+        rx_buf: VecPacketBuffer,
+        tx_writer: SerialWriter<FakeSerial>,
+        tx_queue: VecPacketQueue,
+    }
+
+    impl SerialStorage {
+        fn new() -> Self {
+            Self {
+                rx_buf: VecPacketBuffer::new(66),
+                tx_writer: SerialWriter::new(FakeSerial::default()),
+                tx_queue: VecPacketQueue::new(4, 64),
+            }
+        }
+    }
+
+    impl Storage for SerialStorage {
+        fn rx_buf(&mut self) -> &mut dyn PacketBuffer {
+            &mut self.rx_buf
+        }
+
+        fn tx_writer(&mut self) -> &mut dyn PacketWriter {
+            &mut self.tx_writer
+        }
+
+        fn tx_queue(&mut self) -> &mut dyn PacketQueue {
+            &mut self.tx_queue
+        }
+    }
+
+    #[test]
+    fn test_writer() {
+        setup_log();
+        let mut writer = SerialWriter::new(FakeSerial::default());
+        writer.write_packet_data(0xc0, &[]);
+        assert_eq!(writer.get_ref().tx, &[SOF, 0xc0, 0x74, 0x36, SOF]);
+        assert_eq!(writer.into_inner().flushes, 1);
+    }
+
+    #[test]
+    fn test_pump() {
+        setup_log();
+        let mut serial = FakeSerial::default();
+        let mut storage = SerialStorage::new();
+        let mut ep = EndPoint::new();
+
+        // Nothing has arrived yet.
+        assert_eq!(
+            pump(&mut serial, &mut ep, &mut storage),
+            Err(nb::Error::WouldBlock)
+        );
+
+        // Remote SYN0, followed by a SYN2 and 2 user packets.
+        serial.rx.extend(&[SOF, 0xc0, 0x74, 0x36, SOF]);
+        serial.rx.extend(&[SOF, 0xc2, 0x66, 0x15, SOF]);
+        serial
+            .rx
+            .extend(&[SOF, 0x00, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67]);
+        serial.rx.extend(&[0xc5, 0x5c, SOF, SOF, 0x01]);
+        assert_eq!(pump(&mut serial, &mut ep, &mut storage), Ok(()));
+        assert!(ep.is_connected());
+        assert_eq!(storage.rx_buf.data(), b"Testing");
+
+        // Our SYN1 response was written to the serial port.
+        assert_eq!(
+            storage.tx_writer.get_ref().tx,
+            &[SOF, 0xc1, 0xfd, 0x27, SOF]
+        );
+
+        // The second packet is incomplete.
+        assert_eq!(
+            pump(&mut serial, &mut ep, &mut storage),
+            Err(nb::Error::WouldBlock)
+        );
+        assert!(serial.rx.is_empty());
+    }
+}
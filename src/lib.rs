@@ -1,30 +1,68 @@
 #![no_std]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 
+use core::cmp::min;
 use log::{debug, error, warn};
 
 #[macro_use]
 pub mod macros;
 
+#[cfg(feature = "embedded-io-async")]
+pub mod asynclink;
+#[cfg(feature = "tokio")]
+pub mod codec;
 pub mod crc;
+#[cfg(any(test, feature = "std"))]
+pub mod decode;
 pub mod driver;
+pub mod encode;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+#[cfg(feature = "tokio")]
+pub mod link;
+#[cfg(feature = "serde")]
+pub mod message;
+pub mod mux;
 pub mod packet;
+#[cfg(any(test, feature = "std"))]
+pub mod pcapng;
 pub mod rawpacket;
+#[cfg(any(test, feature = "std"))]
+pub mod record;
+pub mod rpc;
+pub mod scheduler;
+#[cfg(any(test, feature = "std"))]
+pub mod sim;
+#[cfg(feature = "critical-section")]
+pub mod split;
+#[cfg(feature = "std")]
+pub mod stream;
 pub mod traits;
+pub mod transfer;
+#[cfg(any(test, feature = "std"))]
+pub mod vecstorage;
 
+#[cfg(test)]
+mod modelcheck;
+#[cfg(test)]
+mod regression;
 #[cfg(test)]
 mod testutils;
 
-use crc::CrcAccum;
+use crc::{Crc, CrcAccum};
 use packet::{FrameType, PacketParser, PacketType, PacketTypeResult, SeqSyn, SEQ_MASK};
-use traits::{PacketWriter, Storage};
+use traits::{Storage, SOF};
 
 const SEQ_INIT: u8 = 0;
 
-#[derive(PartialEq)]
+// Size of the stack buffer used to copy packets out of the history when
+// retransmitting them.
+const RTX_CHUNK_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
 enum ConnectState {
     Disconnected,
     SentSyn0,
@@ -32,7 +70,7 @@ enum ConnectState {
     Connected,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ParseResult {
     UserPacket,
     AbortedPacket,
@@ -41,12 +79,14 @@ pub enum ParseResult {
     MoreDataNeeded,
 }
 
+#[derive(Clone, Copy)]
 pub struct Transmitter {
     connect_state: ConnectState,
     rx_seq: u8,
     tx_seq: u8,
 }
 
+#[derive(Clone)]
 struct Receiver {
     parser: PacketParser,
 }
@@ -63,8 +103,149 @@ impl Receiver {
     }
 }
 
+// Frames which need to be sent in response to the frames received. The
+// split EndPoint accumulates these until the transmit half is polled, so
+// requests for the same control frame are coalesced.
+#[derive(Clone, Copy, Default)]
+struct Requests {
+    clear_history: bool,
+    syn0: bool,
+    syn1: bool,
+    syn2: bool,
+    dis: bool,
+    nak: Option<u8>,
+    rtx_from: Option<u8>,
+}
+
+impl Requests {
+    const NONE: Self = Self {
+        clear_history: false,
+        syn0: false,
+        syn1: false,
+        syn2: false,
+        dis: false,
+        nak: None,
+        rtx_from: None,
+    };
+
+    // If a retransmission is already pending, keep whichever one starts
+    // further back.
+    fn rtx_from(&mut self, seq: u8, tx_seq: u8) {
+        let count = |seq: u8| tx_seq.wrapping_sub(seq) & SEQ_MASK;
+        match self.rtx_from {
+            Some(pending) if count(pending) >= count(seq) => {}
+            _ => self.rtx_from = Some(seq),
+        }
+    }
+
+    fn send<R: Responder + ?Sized>(self, tx_seq: u8, responder: &mut R) {
+        if self.clear_history {
+            responder.clear_history();
+        }
+        if self.syn0 {
+            responder.write_control(FrameType::SYN as u8 | SeqSyn::SYN0 as u8);
+        }
+        if self.syn1 {
+            responder.write_control(FrameType::SYN as u8 | SeqSyn::SYN1 as u8);
+        }
+        if self.syn2 {
+            responder.write_control(FrameType::SYN as u8 | SeqSyn::SYN2 as u8);
+        }
+        if self.dis {
+            responder.write_control(FrameType::SYN as u8 | SeqSyn::DIS as u8);
+        }
+        if let Some(seq) = self.nak {
+            responder.write_control(FrameType::NAK as u8 | seq);
+        }
+        if let Some(seq) = self.rtx_from {
+            let history_len = responder.history_len();
+            for_each_rtx(seq, tx_seq, history_len, |offset, header| {
+                responder.retransmit(offset, header);
+            });
+        }
+    }
+}
+
+// Sends the frames recorded in Requests.
+trait Responder {
+    fn clear_history(&mut self);
+
+    fn write_control(&mut self, header: u8);
+
+    fn history_len(&mut self) -> usize;
+
+    // Writes the packet at `offset` in the history as a frame with `header`.
+    fn retransmit(&mut self, offset: usize, header: u8);
+}
+
+impl Responder for dyn Storage + '_ {
+    fn clear_history(&mut self) {
+        self.tx_queue().clear();
+    }
+
+    fn write_control(&mut self, header: u8) {
+        self.tx_writer().write_packet_data(header, &[]);
+    }
+
+    fn history_len(&mut self) -> usize {
+        self.tx_queue().len()
+    }
+
+    // The history and the writer can't be borrowed from the storage at the
+    // same time, so the payload is copied out a chunk at a time.
+    fn retransmit(&mut self, offset: usize, header: u8) {
+        let mut crc = Crc::new();
+        let writer = self.tx_writer();
+        writer.start_write();
+        writer.write_byte(SOF);
+        writer.write_escaped_byte(&mut crc, header);
+        let mut chunk = [0u8; RTX_CHUNK_SIZE];
+        let mut pos = 0;
+        loop {
+            let chunk_len = match self.tx_queue().get(offset) {
+                Some(packet) => {
+                    let data = packet.data().get(pos..).unwrap_or(&[]);
+                    let chunk_len = min(data.len(), chunk.len());
+                    chunk[..chunk_len].copy_from_slice(&data[..chunk_len]);
+                    chunk_len
+                }
+                None => 0,
+            };
+            if chunk_len == 0 {
+                break;
+            }
+            self.tx_writer()
+                .write_escaped_bytes(&mut crc, &chunk[..chunk_len]);
+            pos += chunk_len;
+        }
+        let writer = self.tx_writer();
+        writer.write_crc(&mut crc);
+        writer.write_byte(SOF);
+        writer.end_write();
+    }
+}
+
+// Calls `transmit` with the history offset and RTX header of each packet
+// from `seq` up to the most recently sent one, oldest first. The most
+// recently sent packet is at offset 0 in the history and has a sequence
+// number of tx_seq - 1.
+fn for_each_rtx(seq: u8, tx_seq: u8, history_len: usize, mut transmit: impl FnMut(usize, u8)) {
+    let count = tx_seq.wrapping_sub(seq) & SEQ_MASK;
+    if count as usize > history_len {
+        error!(
+            "Unable to retransmit from seq {} - only {} packets in history",
+            seq, history_len
+        );
+        return;
+    }
+    for offset in (0..count).rev() {
+        let rtx_seq = tx_seq.wrapping_sub(offset + 1) & SEQ_MASK;
+        transmit(offset as usize, FrameType::RTX as u8 | rtx_seq);
+    }
+}
+
 impl Transmitter {
-    fn new() -> Self {
+    const fn new() -> Self {
         Self {
             connect_state: ConnectState::Disconnected,
             rx_seq: SEQ_INIT,
@@ -72,15 +253,20 @@ impl Transmitter {
         }
     }
 
-    fn reset(&mut self, storage: &mut dyn Storage) {
-        self.connect_state = ConnectState::Disconnected;
-        self.rx_seq = SEQ_INIT;
-        self.tx_seq = SEQ_INIT;
-        self.clear_history(storage);
+    fn next_frame_seq(&self, seq: u8) -> u8 {
+        (seq + 1) & SEQ_MASK
     }
 
-    fn next_frame_seq(&self, seq: u8) -> u8 {
-        return (seq + 1) & SEQ_MASK;
+    // Starts the handshake with the remote side.
+    fn connect(&mut self, requests: &mut Requests) {
+        self.connect_state = ConnectState::SentSyn0;
+        self.rx_seq = SEQ_INIT;
+        self.tx_seq = SEQ_INIT;
+        *requests = Requests {
+            clear_history: true,
+            syn0: true,
+            ..Requests::NONE
+        };
     }
 
     pub fn handle_packet(
@@ -88,54 +274,87 @@ impl Transmitter {
         packet_type: PacketType,
         storage: &mut dyn Storage,
     ) -> ParseResult {
+        let mut requests = Requests::NONE;
+        let result = self.receive(packet_type, &mut requests);
+        requests.send(self.tx_seq, storage);
+        result
+    }
+
+    // Updates the connection state for a received frame, and adds any
+    // frames which need to be sent in response to `requests`.
+    fn receive(&mut self, packet_type: PacketType, requests: &mut Requests) -> ParseResult {
         debug!("Received {:?}", packet_type);
         match packet_type {
             PacketType::USR { seq } => {
-                return self.handle_frame_usr_rtx(FrameType::USR, seq, storage.tx_writer());
+                return self.receive_usr_rtx(FrameType::USR, seq, requests);
             }
             PacketType::RTX { seq } => {
-                return self.handle_frame_usr_rtx(FrameType::RTX, seq, storage.tx_writer());
+                return self.receive_usr_rtx(FrameType::RTX, seq, requests);
             }
             PacketType::NAK { seq } => {
-                self.handle_frame_nak(seq, storage);
+                if self.connect_state == ConnectState::Connected {
+                    // The other side is telling us the sequence number that
+                    // it expected to receive next, so resend everything
+                    // from there on.
+                    warn!("NAK received - retransmitting from seq {}", seq);
+                    requests.rtx_from(seq, self.tx_seq);
+                }
             }
             PacketType::Syn0 => {
-                self.handle_frame_syn0(storage);
+                self.rx_seq = SEQ_INIT;
+                self.tx_seq = SEQ_INIT;
+                self.connect_state = ConnectState::SentSyn1;
+                *requests = Requests {
+                    clear_history: true,
+                    syn1: true,
+                    ..Requests::NONE
+                };
             }
             PacketType::Syn1 => {
-                self.handle_frame_syn1(storage);
-            }
-            PacketType::Syn2 => {
-                self.handle_frame_syn2(storage);
+                if self.connect_state == ConnectState::Disconnected {
+                    requests.dis = true;
+                } else {
+                    self.connect_state = ConnectState::Connected;
+                    debug!("Connected (after SYN1)");
+                    requests.syn2 = true;
+                    if self.tx_seq != SEQ_INIT {
+                        requests.rtx_from(SEQ_INIT, self.tx_seq);
+                    }
+                }
             }
+            PacketType::Syn2 => match self.connect_state {
+                ConnectState::Disconnected => requests.dis = true,
+                ConnectState::SentSyn0 => requests.syn0 = true,
+                _ => {
+                    self.connect_state = ConnectState::Connected;
+                    debug!("Connected (after SYN2)");
+                    if self.tx_seq != SEQ_INIT {
+                        requests.rtx_from(SEQ_INIT, self.tx_seq);
+                    }
+                }
+            },
             PacketType::Disconnect => {
-                self.handle_frame_disconnect();
+                self.connect_state = ConnectState::Disconnected;
             }
         }
         ParseResult::MoreDataNeeded
     }
 
-    fn handle_frame_usr_rtx(
+    fn receive_usr_rtx(
         &mut self,
         frame_type: FrameType,
         seq: u8,
-        writer: &mut dyn PacketWriter,
+        requests: &mut Requests,
     ) -> ParseResult {
         match self.connect_state {
-            ConnectState::Disconnected => {
-                self.transmit_dis(writer);
-            }
-            ConnectState::SentSyn0 => {
-                self.transmit_syn0(writer);
-            }
-            ConnectState::SentSyn1 => {
-                self.transmit_syn1(writer);
-            }
+            ConnectState::Disconnected => requests.dis = true,
+            ConnectState::SentSyn0 => requests.syn0 = true,
+            ConnectState::SentSyn1 => requests.syn1 = true,
             ConnectState::Connected => {
                 if seq != self.rx_seq {
                     if frame_type == FrameType::USR {
                         warn!("Out of order frame received - sending NAK");
-                        self.transmit_nak(self.rx_seq, writer);
+                        requests.nak = Some(self.rx_seq);
                     } else {
                         warn!("Out of order retransmitted frame frame received - ignoring");
                     }
@@ -149,97 +368,26 @@ impl Transmitter {
         ParseResult::MoreDataNeeded
     }
 
-    fn handle_frame_nak(&mut self, _seq: u8, _writer: &mut dyn Storage) {
-        //TODO
-    }
-
-    fn handle_frame_syn0(&mut self, storage: &mut dyn Storage) {
-        self.rx_seq = SEQ_INIT;
-        self.tx_seq = SEQ_INIT;
-        self.clear_history(storage);
-        self.connect_state = ConnectState::SentSyn1;
-        self.transmit_syn1(storage.tx_writer());
-    }
-
-    fn handle_frame_syn1(&mut self, storage: &mut dyn Storage) {
-        if self.connect_state == ConnectState::Disconnected {
-            self.transmit_dis(storage.tx_writer());
-            return;
-        }
-        self.connect_state = ConnectState::Connected;
-        debug!("Connected (after SYN1)");
-        self.transmit_syn2(storage.tx_writer());
-        if self.tx_seq != SEQ_INIT {
-            self.transmit_history_from_seq(SEQ_INIT, storage);
-        }
-    }
-
-    fn handle_frame_syn2(&mut self, storage: &mut dyn Storage) {
-        if self.connect_state == ConnectState::Disconnected {
-            self.transmit_dis(storage.tx_writer());
-            return;
-        }
-        if self.connect_state == ConnectState::SentSyn0 {
-            self.transmit_syn0(storage.tx_writer());
-            return;
-        }
-        self.connect_state = ConnectState::Connected;
-        debug!("Connected (after SYN2)");
-        if self.tx_seq != SEQ_INIT {
-            self.transmit_history_from_seq(SEQ_INIT, storage);
-        }
-    }
-
-    fn handle_frame_disconnect(&mut self) {
-        self.connect_state = ConnectState::Disconnected;
-    }
-
-    fn clear_history(&mut self, storage: &mut dyn Storage) {
-        storage.tx_queue().clear();
-    }
-
-    fn transmit_history_from_seq(&mut self, _seq: u8, _storage: &mut dyn Storage) {
-        // TODO
-    }
-
-    fn transmit_nak(&mut self, seq: u8, writer: &mut dyn PacketWriter) {
-        self.transmit_control_packet(FrameType::NAK, seq, writer);
-    }
-
-    fn transmit_dis(&mut self, writer: &mut dyn PacketWriter) {
-        self.transmit_control_packet(FrameType::SYN, SeqSyn::DIS as u8, writer);
-    }
-
-    fn transmit_syn0(&mut self, writer: &mut dyn PacketWriter) {
-        self.transmit_control_packet(FrameType::SYN, SeqSyn::SYN0 as u8, writer);
-    }
-
-    fn transmit_syn1(&mut self, writer: &mut dyn PacketWriter) {
-        self.transmit_control_packet(FrameType::SYN, SeqSyn::SYN1 as u8, writer);
-    }
-
-    fn transmit_syn2(&mut self, writer: &mut dyn PacketWriter) {
-        self.transmit_control_packet(FrameType::SYN, SeqSyn::SYN2 as u8, writer);
-    }
-
-    fn transmit_control_packet(
-        &mut self,
-        frame_type: FrameType,
-        seq: u8,
-        writer: &mut dyn PacketWriter,
-    ) {
-        let header = (frame_type as u8) | (seq & SEQ_MASK);
-        let data: &[u8] = &[];
-
-        writer.write_packet_data(header, data);
+    // Allocates the sequence number for the next user packet.
+    fn allocate_seq(&mut self) -> u8 {
+        let seq = self.tx_seq;
+        self.tx_seq = self.next_frame_seq(seq);
+        seq
     }
 }
 
+#[derive(Clone)]
 pub struct EndPoint {
     tx: Transmitter,
     rx: Receiver,
 }
 
+impl Default for EndPoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EndPoint {
     pub fn new() -> Self {
         Self {
@@ -249,18 +397,37 @@ impl EndPoint {
     }
 
     pub fn connect(&mut self, storage: &mut dyn Storage) {
-        self.tx.reset(storage);
         self.rx.reset();
-        self.tx.transmit_syn0(storage.tx_writer());
-        self.tx.connect_state = ConnectState::SentSyn0;
+        let mut requests = Requests::NONE;
+        self.tx.connect(&mut requests);
+        requests.send(self.tx.tx_seq, storage);
     }
 
     pub fn is_connected(&self) -> bool {
-        return self.tx.connect_state == ConnectState::Connected;
+        self.tx.connect_state == ConnectState::Connected
     }
 
-    pub fn parse_byte<'a>(&mut self, byte: u8, storage: &mut dyn Storage) -> ParseResult {
+    pub fn parse_byte(&mut self, byte: u8, storage: &mut dyn Storage) -> ParseResult {
         let parse_result = self.rx.parser.parse_byte(byte, storage.rx_buf());
+        self.handle_parse_result(parse_result, storage)
+    }
+
+    /// Parses received bytes until a frame is complete, scanning runs of
+    /// payload in bulk. Returns the number of bytes consumed along with the
+    /// result for that frame. The result is MoreDataNeeded for control
+    /// frames as well as when all of the bytes were consumed, so callers
+    /// should keep calling this until all of the bytes have been consumed.
+    /// Any replies are written before returning.
+    pub fn parse_bytes(&mut self, bytes: &[u8], storage: &mut dyn Storage) -> (usize, ParseResult) {
+        let (consumed, parse_result) = self.rx.parser.parse_bytes(bytes, storage.rx_buf());
+        (consumed, self.handle_parse_result(parse_result, storage))
+    }
+
+    fn handle_parse_result(
+        &mut self,
+        parse_result: PacketTypeResult,
+        storage: &mut dyn Storage,
+    ) -> ParseResult {
         match parse_result {
             PacketTypeResult::PacketReceived(packet_type) => {
                 self.tx.handle_packet(packet_type, storage)
@@ -272,18 +439,39 @@ impl EndPoint {
         }
     }
 
+    /// Retransmits the `count` most recently sent packets from the history
+    /// as RTX frames. The remote side ignores any which it already has.
+    /// There are no acknowledgements, so a lost packet is normally only
+    /// noticed (and NAKed) when a later packet arrives. This allows the
+    /// last packets of a burst to be recovered, e.g. after a timeout.
+    pub fn retransmit_last(&mut self, count: usize, storage: &mut dyn Storage) {
+        if !self.is_connected() {
+            return;
+        }
+        let count = min(min(count, storage.tx_queue().len()), SEQ_MASK as usize) as u8;
+        let requests = Requests {
+            rtx_from: Some(self.tx.tx_seq.wrapping_sub(count) & SEQ_MASK),
+            ..Requests::NONE
+        };
+        requests.send(self.tx.tx_seq, storage);
+    }
+
     pub fn write_packet(&mut self, data: &[u8], storage: &mut dyn Storage) {
+        self.write_packet_vectored(&[data], storage);
+    }
+
+    /// Writes a user packet whose payload is the concatenation of `bufs`.
+    /// This allows a packet to be built from several pieces (e.g. a header
+    /// and a body) without having to copy them into a temporary buffer.
+    pub fn write_packet_vectored(&mut self, bufs: &[&[u8]], storage: &mut dyn Storage) {
         if !self.is_connected() {
             error!("Not connected");
             return;
         }
-        let header: u8 = FrameType::USR as u8 | self.tx.tx_seq;
-
-        let tx_buf = storage.tx_queue().next();
-        tx_buf.store_data(data);
+        let header: u8 = FrameType::USR as u8 | self.tx.allocate_seq();
 
-        storage.tx_writer().write_packet_data(header, data);
-        self.tx.tx_seq = self.tx.next_frame_seq(self.tx.tx_seq);
+        storage.tx_queue().next().store_data_vectored(bufs);
+        storage.tx_writer().write_packet_data_vectored(header, bufs);
     }
 }
 
@@ -297,44 +485,10 @@ impl EndPoint {
 mod tests {
     use super::*;
     use crate::testutils::{setup_log, TestStorage};
-    use crate::traits::SOF;
+    use crate::traits::{PacketWriter, ESC};
+    use crate::vecstorage::VecStorage;
     use log::info;
-
-    impl EndPoint {
-        // Parse a bunch of bytes and return the first return code that isn't
-        // MoreDataNeeded. This means that this function will parse at most one
-        // error or packet from the input stream, which is fine for testing.
-
-        // bytes, rx_packet, writer
-        pub fn parse_bytes(&mut self, bytes: &[u8], storage: &mut dyn Storage) -> ParseResult {
-            storage.tx_writer().start_write(); // Clears the outout buffer.
-            for byte in bytes.iter() {
-                let parse_result = self.parse_byte(*byte, storage);
-                match parse_result {
-                    ParseResult::UserPacket => {
-                        return ParseResult::UserPacket;
-                    }
-
-                    ParseResult::MoreDataNeeded => {
-                        continue;
-                    }
-
-                    ParseResult::AbortedPacket => {
-                        return ParseResult::AbortedPacket;
-                    }
-
-                    ParseResult::PacketTooSmall => {
-                        return ParseResult::PacketTooSmall;
-                    }
-
-                    ParseResult::CrcError(rcvd_crc) => {
-                        return ParseResult::CrcError(rcvd_crc);
-                    }
-                }
-            }
-            ParseResult::MoreDataNeeded
-        }
-    }
+    use std::vec::Vec;
 
     #[test]
     fn test() {
@@ -355,14 +509,14 @@ mod tests {
 
         // Sending the SYN0 to the other side, should generate a SYN1 in response
         assert_eq!(
-            ep2.parse_bytes(storage1.tx_data(), &mut storage2),
+            ep2.parse_packet(storage1.tx_data(), &mut storage2),
             ParseResult::MoreDataNeeded
         );
         assert_eq!(storage2.tx_vec(), vec![SOF, 0xc1, 0xfd, 0x27, SOF]);
 
         // Sending SYN1 to initial side should generate a SYN2 in response Side 1 should be connected
         assert_eq!(
-            ep1.parse_bytes(storage2.tx_data(), &mut storage1),
+            ep1.parse_packet(storage2.tx_data(), &mut storage1),
             ParseResult::MoreDataNeeded
         );
         assert!(ep1.is_connected());
@@ -370,7 +524,7 @@ mod tests {
 
         // Sending the SYN2 to Side 2 should then put it into a connected state
         assert_eq!(
-            ep2.parse_bytes(storage1.tx_data(), &mut storage2),
+            ep2.parse_packet(storage1.tx_data(), &mut storage2),
             ParseResult::MoreDataNeeded
         );
         assert!(ep2.is_connected());
@@ -384,7 +538,7 @@ mod tests {
             vec![SOF, 0x00, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67, 0xc5, 0x5c, SOF]
         );
         assert_eq!(
-            ep2.parse_bytes(storage1.tx_data(), &mut storage2),
+            ep2.parse_packet(storage1.tx_data(), &mut storage2),
             ParseResult::UserPacket
         );
         assert_eq!(storage2.rx_data(), "Testing".as_bytes());
@@ -397,4 +551,256 @@ mod tests {
 
         //info!("packet1to2: {:?}", packet1to2.dump());
     }
+
+    // Feeds all of the bytes written to `from` into `ep`, returning the user
+    // packets which were received.
+    fn pump(from: &mut VecStorage, ep: &mut EndPoint, storage: &mut VecStorage) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let bytes = from.take_tx_data();
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let (consumed, parse_result) = ep.parse_bytes(&bytes[idx..], storage);
+            idx += consumed;
+            if parse_result == ParseResult::UserPacket {
+                packets.push(storage.rx_data().to_vec());
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut storage2 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        ep1.connect(&mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+
+        // Each call stops at the end of a frame, so the packet can be
+        // collected before the next one is parsed.
+        ep1.write_packet(b"One", &mut storage1);
+        let first_len = storage1.tx_data().len();
+        ep1.write_packet(&[SOF, ESC], &mut storage1);
+        let bytes = storage1.take_tx_data();
+        assert_eq!(
+            ep2.parse_bytes(&bytes, &mut storage2),
+            (first_len, ParseResult::UserPacket)
+        );
+        assert_eq!(storage2.rx_buf().data(), b"One");
+        assert_eq!(
+            ep2.parse_bytes(&bytes[first_len..], &mut storage2),
+            (bytes.len() - first_len, ParseResult::UserPacket)
+        );
+        assert_eq!(storage2.rx_buf().data(), [SOF, ESC]);
+
+        // A partial frame consumes everything.
+        ep1.write_packet(b"Three", &mut storage1);
+        let bytes = storage1.take_tx_data();
+        assert_eq!(
+            ep2.parse_bytes(&bytes[..4], &mut storage2),
+            (4, ParseResult::MoreDataNeeded)
+        );
+        assert_eq!(
+            ep2.parse_bytes(&bytes[4..], &mut storage2),
+            (bytes.len() - 4, ParseResult::UserPacket)
+        );
+        assert_eq!(storage2.rx_buf().data(), b"Three");
+    }
+
+    #[test]
+    fn test_nak_retransmit() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut storage2 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        ep1.connect(&mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+        assert!(ep1.is_connected());
+        assert!(ep2.is_connected());
+
+        // Lose the first packet. The other 2 packets are out of order, so
+        // each one causes a NAK to be sent.
+        ep1.write_packet(b"One", &mut storage1);
+        storage1.take_tx_data();
+        ep1.write_packet(b"Two", &mut storage1);
+        ep1.write_packet(b"Three", &mut storage1);
+        assert!(pump(&mut storage1, &mut ep2, &mut storage2).is_empty());
+        assert_eq!(
+            storage2.tx_data(),
+            &[SOF, 0x80, 0x70, 0x74, SOF, SOF, 0x80, 0x70, 0x74, SOF]
+        );
+
+        // Each NAK causes all 3 packets to be retransmitted. The duplicates
+        // from the second NAK are ignored.
+        assert!(pump(&mut storage2, &mut ep1, &mut storage1).is_empty());
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![b"One".to_vec(), b"Two".to_vec(), b"Three".to_vec()]
+        );
+        assert!(storage2.tx_data().is_empty());
+
+        // Things continue normally after the retransmission.
+        ep1.write_packet(b"Four", &mut storage1);
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![b"Four".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_write_vectored() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut storage2 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        ep1.connect(&mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+
+        // A vectored write produces the same frame as writing the
+        // concatenated payload.
+        let mut expected = Vec::new();
+        expected.write_packet_data(0x00, b"Testing");
+        ep1.write_packet_vectored(&[b"Te", b"", b"sting"], &mut storage1);
+        assert_eq!(storage1.tx_data(), &expected[..]);
+        ep1.write_packet_vectored(&[b"Te", &[SOF], b"sting"], &mut storage1);
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![b"Testing".to_vec(), b"Te\x7esting".to_vec()]
+        );
+
+        // The history holds the concatenated payload, so it can be
+        // retransmitted when lost.
+        ep1.write_packet_vectored(&[b"Ab", b"c"], &mut storage1);
+        storage1.take_tx_data();
+        ep1.write_packet(b"d", &mut storage1);
+        assert!(pump(&mut storage1, &mut ep2, &mut storage2).is_empty());
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![b"Abc".to_vec(), b"d".to_vec()]
+        );
+    }
+
+    // Connects ep1 to ep2, and then sends packets from ep1 with the first
+    // one lost, so that ep2 replies with a NAK.
+    fn lose_first(
+        packets: &[&[u8]],
+        ep1: &mut EndPoint,
+        storage1: &mut VecStorage,
+        ep2: &mut EndPoint,
+        storage2: &mut VecStorage,
+    ) {
+        ep1.connect(storage1);
+        pump(storage1, ep2, storage2);
+        pump(storage2, ep1, storage1);
+        pump(storage1, ep2, storage2);
+        for (idx, packet) in packets.iter().enumerate() {
+            ep1.write_packet(packet, storage1);
+            if idx == 0 {
+                storage1.take_tx_data();
+            }
+        }
+        assert!(pump(storage1, ep2, storage2).is_empty());
+    }
+
+    #[test]
+    fn test_rtx_large_packet() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(100, 4);
+        let mut storage2 = VecStorage::new(100, 4);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        // The packet is copied out of the history in chunks, so make sure
+        // that escaped bytes on either side of a chunk boundary survive.
+        let large: Vec<u8> = (0..100)
+            .map(|i| if i % 31 < 2 { SOF } else { i as u8 })
+            .collect();
+        lose_first(
+            &[&large, b"Next"],
+            &mut ep1,
+            &mut storage1,
+            &mut ep2,
+            &mut storage2,
+        );
+        pump(&mut storage2, &mut ep1, &mut storage1);
+
+        let mut expected = Vec::new();
+        expected.write_packet_data(FrameType::RTX as u8, &large);
+        expected.write_packet_data(FrameType::RTX as u8 | 1, b"Next");
+        assert_eq!(storage1.tx_data(), &expected[..]);
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![large, b"Next".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_nak_beyond_history() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(64, 2);
+        let mut storage2 = VecStorage::new(64, 2);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        // The lost packet has already dropped out of the 2 packet history,
+        // so nothing can be retransmitted.
+        lose_first(
+            &[b"One", b"Two", b"Three"],
+            &mut ep1,
+            &mut storage1,
+            &mut ep2,
+            &mut storage2,
+        );
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        assert!(storage1.tx_data().is_empty());
+    }
+
+    #[test]
+    fn test_retransmit_last() {
+        setup_log();
+
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut storage2 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+
+        ep1.connect(&mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+        pump(&mut storage2, &mut ep1, &mut storage1);
+        pump(&mut storage1, &mut ep2, &mut storage2);
+
+        // Nothing follows the lost packet, so no NAK is sent for it.
+        ep1.write_packet(b"One", &mut storage1);
+        ep1.write_packet(b"Two", &mut storage1);
+        storage1.take_tx_data();
+        ep1.retransmit_last(2, &mut storage1);
+        assert_eq!(
+            pump(&mut storage1, &mut ep2, &mut storage2),
+            vec![b"One".to_vec(), b"Two".to_vec()]
+        );
+
+        // Packets which were already received are ignored.
+        ep1.retransmit_last(10, &mut storage1);
+        assert!(pump(&mut storage1, &mut ep2, &mut storage2).is_empty());
+        assert!(storage2.tx_data().is_empty());
+    }
 }
@@ -0,0 +1,541 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_core::Stream;
+use futures_sink::Sink;
+use log::{debug, error, info};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use std::vec::Vec;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::vecstorage::VecStorage;
+use crate::{EndPoint, ParseResult};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkState {
+    /// The handshake with the remote side is in progress.
+    Connecting,
+    /// User packets can be exchanged with the remote side.
+    Connected,
+    /// The transport was closed or reported an error.
+    Closed,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// Maximum size of a user packet.
+    pub max_packet_size: usize,
+    /// Number of sent packets kept for retransmission.
+    pub history_len: usize,
+    /// How often the handshake is restarted while not connected.
+    pub connect_interval: Duration,
+    /// How often unacknowledged packets are retransmitted while connected.
+    pub retransmit_interval: Duration,
+    /// The Sink isn't ready while more than this many encoded bytes are
+    /// waiting to be written to the transport.
+    pub max_unflushed: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 256,
+            history_len: 8,
+            connect_interval: Duration::from_millis(500),
+            retransmit_interval: Duration::from_millis(500),
+            max_unflushed: 4096,
+        }
+    }
+}
+
+/// An asynchronous reliable link over any AsyncRead + AsyncWrite transport.
+///
+/// The link drives an EndPoint internally: it performs the handshake
+/// (retrying every `connect_interval` until the remote side responds),
+/// answers NAKs with retransmissions, and queues received user packets.
+/// Since a packet lost with nothing sent after it can't be NAKed, the
+/// packets sent since the remote side was last heard from are retransmitted
+/// every `retransmit_interval` until something is received.
+/// Received packets are available through the Stream implementation and
+/// packets are sent through the Sink implementation.
+///
+/// The Stream and Sink share the transport, so they should be polled from
+/// the same task (e.g. using `select!`).
+pub struct SfpLink<T> {
+    transport: T,
+    endpoint: EndPoint,
+    storage: VecStorage,
+    read_buf: Vec<u8>,
+    received: VecDeque<Vec<u8>>,
+    state: watch::Sender<LinkState>,
+    connect_timer: Pin<Box<Sleep>>,
+    connect_interval: Duration,
+    retransmit_timer: Pin<Box<Sleep>>,
+    retransmit_interval: Duration,
+    // The number of packets sent since the remote side was last heard from.
+    unacked: usize,
+    max_unflushed: usize,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SfpLink<T> {
+    /// Creates a link using the default configuration and starts the
+    /// handshake. This needs to be called from within a tokio runtime.
+    pub fn new(transport: T) -> Self {
+        Self::with_config(transport, LinkConfig::default())
+    }
+
+    /// Creates a link using the indicated configuration and starts the
+    /// handshake. This needs to be called from within a tokio runtime.
+    pub fn with_config(transport: T, config: LinkConfig) -> Self {
+        let (state, _) = watch::channel(LinkState::Connecting);
+        let mut link = Self {
+            transport,
+            endpoint: EndPoint::new(),
+            storage: VecStorage::new(config.max_packet_size, config.history_len),
+            read_buf: vec![0; 1024],
+            received: VecDeque::new(),
+            state,
+            connect_timer: Box::pin(sleep(config.connect_interval)),
+            connect_interval: config.connect_interval,
+            retransmit_timer: Box::pin(sleep(config.retransmit_interval)),
+            retransmit_interval: config.retransmit_interval,
+            unacked: 0,
+            max_unflushed: config.max_unflushed,
+        };
+        link.endpoint.connect(&mut link.storage);
+        link
+    }
+
+    /// Returns the current state of the link.
+    pub fn state(&self) -> LinkState {
+        *self.state.borrow()
+    }
+
+    /// Returns a receiver which can be used to watch for changes to the
+    /// state of the link.
+    pub fn state_changes(&self) -> watch::Receiver<LinkState> {
+        self.state.subscribe()
+    }
+
+    /// Returns a reference to the transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    fn set_state(&mut self, state: LinkState) {
+        if self.state() != state {
+            info!("Link state: {:?}", state);
+            if state == LinkState::Connecting {
+                // Give the remote side a chance to finish the handshake
+                // before we start a new one.
+                let deadline = Instant::now() + self.connect_interval;
+                self.connect_timer.as_mut().reset(deadline);
+            }
+            self.state.send_replace(state);
+        }
+    }
+
+    fn set_closed(&mut self, err: Option<io::Error>) {
+        if let Some(err) = err {
+            error!("Link transport error: {}", err);
+        }
+        self.set_state(LinkState::Closed);
+    }
+
+    fn poll_write_tx(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.storage.tx_data().is_empty() {
+            let bytes_written =
+                match Pin::new(&mut self.transport).poll_write(cx, self.storage.tx_data()) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => return Poll::Pending,
+                };
+            if bytes_written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.storage.consume_tx_data(bytes_written);
+        }
+        Pin::new(&mut self.transport).poll_flush(cx)
+    }
+
+    // Processes whatever input is available, runs the handshake timer and
+    // writes out any pending output. This never blocks. The task will be
+    // woken when there is more work to do.
+    fn poll_drive(&mut self, cx: &mut Context<'_>) {
+        if self.state() == LinkState::Closed {
+            return;
+        }
+
+        if !self.endpoint.is_connected() && self.connect_timer.as_mut().poll(cx).is_ready() {
+            debug!("Restarting handshake");
+            self.endpoint.connect(&mut self.storage);
+            let deadline = Instant::now() + self.connect_interval;
+            self.connect_timer.as_mut().reset(deadline);
+            // Register the new deadline with the task.
+            let _ = self.connect_timer.as_mut().poll(cx);
+        }
+
+        if self.endpoint.is_connected()
+            && self.unacked > 0
+            && self.retransmit_timer.as_mut().poll(cx).is_ready()
+        {
+            debug!("Retransmitting {} packets", self.unacked);
+            self.endpoint
+                .retransmit_last(self.unacked, &mut self.storage);
+            let deadline = Instant::now() + self.retransmit_interval;
+            self.retransmit_timer.as_mut().reset(deadline);
+            let _ = self.retransmit_timer.as_mut().poll(cx);
+        }
+
+        loop {
+            let mut buf = ReadBuf::new(&mut self.read_buf);
+            match Pin::new(&mut self.transport).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {
+                    let bytes_read = buf.filled().len();
+                    if bytes_read == 0 {
+                        self.set_closed(None);
+                        return;
+                    }
+                    self.unacked = 0;
+                    let mut idx = 0;
+                    while idx < bytes_read {
+                        let (consumed, parse_result) = self
+                            .endpoint
+                            .parse_bytes(&self.read_buf[idx..bytes_read], &mut self.storage);
+                        idx += consumed;
+                        if parse_result == ParseResult::UserPacket {
+                            self.received.push_back(self.storage.rx_data().to_vec());
+                        }
+                    }
+                }
+                Poll::Ready(Err(err)) => {
+                    self.set_closed(Some(err));
+                    return;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if self.endpoint.is_connected() {
+            self.set_state(LinkState::Connected);
+        } else {
+            self.set_state(LinkState::Connecting);
+        }
+
+        if let Poll::Ready(Err(err)) = self.poll_write_tx(cx) {
+            self.set_closed(Some(err));
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for SfpLink<T> {
+    type Item = Vec<u8>;
+
+    /// Returns the next user packet received from the remote side. The
+    /// stream ends when the link is closed.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.poll_drive(cx);
+        if let Some(packet) = this.received.pop_front() {
+            return Poll::Ready(Some(packet));
+        }
+        if this.state() == LinkState::Closed {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "link closed")
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Sink<Vec<u8>> for SfpLink<T> {
+    type Error = io::Error;
+
+    /// Waits until the link is connected and no more than `max_unflushed`
+    /// bytes are waiting to be written to the transport.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_drive(cx);
+        match this.state() {
+            // poll_drive registered for a wakeup when the transport can
+            // accept more data.
+            LinkState::Connected if this.storage.tx_data().len() > this.max_unflushed => {
+                Poll::Pending
+            }
+            LinkState::Connected => Poll::Ready(Ok(())),
+            LinkState::Connecting => Poll::Pending,
+            LinkState::Closed => Poll::Ready(Err(closed_error())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+        let this = self.get_mut();
+        if !this.endpoint.is_connected() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "link not connected",
+            ));
+        }
+        this.endpoint.write_packet(&item, &mut this.storage);
+        if this.unacked == 0 {
+            let deadline = Instant::now() + this.retransmit_interval;
+            this.retransmit_timer.as_mut().reset(deadline);
+        }
+        this.unacked += 1;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_drive(cx);
+        if this.state() == LinkState::Closed {
+            return Poll::Ready(Err(closed_error()));
+        }
+        this.poll_write_tx(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.state() != LinkState::Closed {
+            match this.poll_write_tx(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+        }
+        let result = Pin::new(&mut this.transport).poll_shutdown(cx);
+        if result.is_ready() {
+            this.set_closed(None);
+        }
+        result
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::SOF;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    // The remote side of a link, driven by hand.
+    struct Peer {
+        io: DuplexStream,
+        endpoint: EndPoint,
+        storage: VecStorage,
+    }
+
+    impl Peer {
+        fn new(io: DuplexStream) -> Self {
+            Self {
+                io,
+                endpoint: EndPoint::new(),
+                storage: VecStorage::new(256, 8),
+            }
+        }
+
+        // Reads until a user packet arrives, answering the handshake and
+        // NAKs along the way.
+        async fn recv(&mut self) -> Vec<u8> {
+            let mut buf = [0u8; 256];
+            loop {
+                let bytes_read = self.io.read(&mut buf).await.unwrap();
+                assert!(bytes_read > 0);
+                let mut packet = None;
+                for byte in buf[..bytes_read].iter() {
+                    if self.endpoint.parse_byte(*byte, &mut self.storage) == ParseResult::UserPacket
+                    {
+                        packet.get_or_insert_with(|| self.storage.rx_data().to_vec());
+                    }
+                }
+                let tx_data = self.storage.take_tx_data();
+                self.io.write_all(&tx_data).await.unwrap();
+                if let Some(packet) = packet {
+                    return packet;
+                }
+            }
+        }
+
+        async fn send(&mut self, data: &[u8]) {
+            self.endpoint.write_packet(data, &mut self.storage);
+            let tx_data = self.storage.take_tx_data();
+            self.io.write_all(&tx_data).await.unwrap();
+        }
+    }
+
+    async fn connected_link(config: LinkConfig) -> (SfpLink<DuplexStream>, Peer) {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut link = SfpLink::with_config(a, config);
+        let mut peer = Peer::new(b);
+        let (sent, received) = tokio::join!(link.send(b"Hello".to_vec()), peer.recv());
+        sent.unwrap();
+        assert_eq!(received, b"Hello");
+        (link, peer)
+    }
+
+    const SYN0: [u8; 5] = [SOF, 0xc0, 0x74, 0x36, SOF];
+
+    #[tokio::test]
+    async fn test_link() {
+        setup_log();
+        let (a, b) = tokio::io::duplex(64);
+        let mut link_a = SfpLink::new(a);
+        let mut link_b = SfpLink::new(b);
+        let mut state_a = link_a.state_changes();
+        assert_eq!(*state_a.borrow(), LinkState::Connecting);
+
+        // Both sides send SYN0 at the same time. Drive both of them until
+        // they've agreed on a connection.
+        let (send_a, send_b) = tokio::join!(
+            link_a.send(b"Hello from A".to_vec()),
+            link_b.send(b"Hello from B".to_vec())
+        );
+        send_a.unwrap();
+        send_b.unwrap();
+        assert_eq!(*state_a.borrow_and_update(), LinkState::Connected);
+
+        // Large packets don't fit in the duplex buffer, so the receiver
+        // needs to be running while the packet is sent.
+        let big: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let (sent, received) = tokio::join!(
+            link_a.send(big.clone()),
+            (&mut link_b).take(2).collect::<Vec<_>>()
+        );
+        sent.unwrap();
+        assert_eq!(received, vec![b"Hello from A".to_vec(), big]);
+        assert_eq!(link_a.next().await.unwrap(), b"Hello from B");
+
+        // Closing one side ends the stream on the other.
+        link_a.close().await.unwrap();
+        assert_eq!(link_a.state(), LinkState::Closed);
+        assert_eq!(link_b.next().await, None);
+        assert_eq!(link_b.state(), LinkState::Closed);
+        assert!(link_b.send(b"Too late".to_vec()).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_retry() {
+        setup_log();
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut link = SfpLink::with_config(
+            a,
+            LinkConfig {
+                connect_interval: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+
+        // Drive the link in the background while we look at what it sends.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            while let Some(packet) = link.next().await {
+                tx.send(packet).unwrap();
+            }
+        });
+
+        // The handshake is retried until the other side answers.
+        let mut buf = [0u8; 5];
+        let start = Instant::now();
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, SYN0);
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, SYN0);
+        assert!(Instant::now() - start >= Duration::from_millis(100));
+
+        // Answer with a SYN1 and then send a user packet.
+        b.write_all(&[SOF, 0xc1, 0xfd, 0x27, SOF]).await.unwrap();
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [SOF, 0xc2, 0x66, 0x15, SOF]);
+        let usr = [
+            SOF, 0x00, 0x54, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67, 0xc5, 0x5c, SOF,
+        ];
+        b.write_all(&usr).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"Testing");
+
+        drop(b);
+        task.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmit() {
+        setup_log();
+        let (mut link, mut peer) = connected_link(LinkConfig {
+            retransmit_interval: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+
+        // The last packet of a burst gets lost on its way to the peer.
+        link.send(b"Lost".to_vec()).await.unwrap();
+        let mut buf = [0u8; 256];
+        assert!(peer.io.read(&mut buf).await.unwrap() > 0);
+
+        // It's retransmitted after the retransmit interval.
+        let (received, _) = tokio::join!(
+            peer.recv(),
+            timeout(Duration::from_millis(150), link.next())
+        );
+        assert_eq!(received, b"Lost");
+
+        // The link hasn't heard from the peer, so it keeps retransmitting.
+        let (bytes_read, _) = tokio::join!(
+            peer.io.read(&mut buf),
+            timeout(Duration::from_millis(150), link.next())
+        );
+        assert!(bytes_read.unwrap() > 0);
+
+        // Once the peer sends something the retransmissions stop.
+        peer.send(b"Got it").await;
+        assert_eq!(link.next().await.unwrap(), b"Got it");
+        while timeout(Duration::from_millis(1), peer.io.read(&mut buf))
+            .await
+            .is_ok()
+        {}
+        let (bytes_read, _) = tokio::join!(
+            timeout(Duration::from_millis(500), peer.io.read(&mut buf)),
+            timeout(Duration::from_millis(500), link.next())
+        );
+        assert!(bytes_read.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backpressure() {
+        setup_log();
+        let (mut link, mut peer) = connected_link(LinkConfig {
+            max_unflushed: 100,
+            ..Default::default()
+        })
+        .await;
+
+        // Nobody is reading from the transport, so the encoded packets pile
+        // up until the sink stops accepting more.
+        let big: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut sent = 0;
+        while timeout(Duration::from_millis(10), link.feed(big.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 10);
+        }
+        assert!(link.storage.tx_data().len() > 100);
+
+        // Reading from the other side frees up the sink again.
+        let (fed, received) = tokio::join!(link.feed(big.clone()), peer.recv());
+        fed.unwrap();
+        assert_eq!(received, big);
+    }
+}
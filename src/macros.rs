@@ -7,6 +7,8 @@ macro_rules! c_like_enum {
         }
 
         impl $name {
+            pub const VARIANTS: &'static [$name] = &[$($name::$variant,)+];
+
             pub fn from_u8(value: u8) -> Option<$name> {
                 match value {
                     $($value => Some($name::$variant),)+
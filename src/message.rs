@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+use crate::traits::Storage;
+use crate::EndPoint;
+
+#[derive(Debug, PartialEq)]
+pub enum MessageError {
+    /// The EndPoint isn't connected.
+    NotConnected,
+    /// The message couldn't be serialized (typically because it didn't fit
+    /// in the buffer).
+    Serialize(postcard::Error),
+    /// The received packet couldn't be deserialized as the requested type.
+    Deserialize(postcard::Error),
+    /// The received packet was deserialized, but contained the indicated
+    /// number of unused bytes after the end of the message.
+    TrailingBytes(usize),
+}
+
+impl EndPoint {
+    /// Serializes `msg` using postcard and sends it as a user packet. The
+    /// message is serialized into `buf`, which needs to be large enough to
+    /// hold the serialized form of the message.
+    pub fn send_message<T: Serialize>(
+        &mut self,
+        msg: &T,
+        buf: &mut [u8],
+        storage: &mut dyn Storage,
+    ) -> Result<(), MessageError> {
+        if !self.is_connected() {
+            return Err(MessageError::NotConnected);
+        }
+        let data = postcard::to_slice(msg, buf).map_err(MessageError::Serialize)?;
+        self.write_packet(data, storage);
+        Ok(())
+    }
+
+    /// Deserializes the most recently received user packet. This should be
+    /// called after parse_byte returns UserPacket.
+    pub fn decode_message<'de, T: Deserialize<'de>>(
+        &self,
+        storage: &'de mut dyn Storage,
+    ) -> Result<T, MessageError> {
+        let (msg, remaining) = postcard::take_from_bytes(storage.rx_buf().data())
+            .map_err(MessageError::Deserialize)?;
+        if !remaining.is_empty() {
+            return Err(MessageError::TrailingBytes(remaining.len()));
+        }
+        Ok(msg)
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{connect_endpoints, setup_log, TestStorage};
+    use crate::ParseResult;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command<'a> {
+        Reset,
+        SetLed { led: u8, on: bool },
+        Log(&'a str),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: u16,
+        value: i32,
+    }
+
+    #[test]
+    fn test_send_decode() {
+        setup_log();
+        let mut storage1 = TestStorage::new();
+        let mut storage2 = TestStorage::new();
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+        let mut buf = [0u8; 64];
+
+        assert_eq!(
+            ep1.send_message(&Command::Reset, &mut buf, &mut storage1),
+            Err(MessageError::NotConnected)
+        );
+        connect_endpoints(&mut ep1, &mut storage1, &mut ep2, &mut storage2);
+
+        let cmds = [
+            Command::Reset,
+            Command::SetLed { led: 3, on: true },
+            Command::Log("Hello"),
+        ];
+        for cmd in cmds.iter() {
+            ep1.send_message(cmd, &mut buf, &mut storage1).unwrap();
+            assert_eq!(
+                ep2.parse_packet(storage1.tx_data(), &mut storage2),
+                ParseResult::UserPacket
+            );
+            let rcvd: Command = ep2.decode_message(&mut storage2).unwrap();
+            assert_eq!(&rcvd, cmd);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        setup_log();
+        let mut storage1 = TestStorage::new();
+        let mut storage2 = TestStorage::new();
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+        connect_endpoints(&mut ep1, &mut storage1, &mut ep2, &mut storage2);
+
+        // Buffer too small to hold the message.
+        let mut buf = [0u8; 2];
+        let reading = Reading {
+            sensor: 1000,
+            value: -100_000,
+        };
+        assert_eq!(
+            ep1.send_message(&reading, &mut buf, &mut storage1),
+            Err(MessageError::Serialize(
+                postcard::Error::SerializeBufferFull
+            ))
+        );
+
+        // Packet which is too short to be the requested type.
+        ep1.write_packet(&[0x01], &mut storage1);
+        ep2.parse_packet(storage1.tx_data(), &mut storage2);
+        assert_eq!(
+            ep2.decode_message::<Reading>(&mut storage2),
+            Err(MessageError::Deserialize(
+                postcard::Error::DeserializeUnexpectedEnd
+            ))
+        );
+
+        // Packet with extra data after the message.
+        ep1.write_packet(&[0x01, 0x02, 0x03], &mut storage1);
+        ep2.parse_packet(storage1.tx_data(), &mut storage2);
+        assert_eq!(
+            ep2.decode_message::<u8>(&mut storage2),
+            Err(MessageError::TrailingBytes(2))
+        );
+    }
+}
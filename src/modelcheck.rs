@@ -0,0 +1,355 @@
+// An explicit-state model checker for the connection state machine.
+//
+// Two EndPoints are connected by a pair of FIFO wires. From each state, the
+// checker explores every enabled action: either side (or both at once) may
+// call connect(), either side may send a user packet, and the frame at the
+// head of either wire may be delivered or dropped. The number of each kind
+// of action is bounded so that the state space is finite, and each distinct
+// state is explored once.
+//
+// In every reachable state the checker verifies that packets are delivered
+// intact and in order, and then that the endpoints converge: once the
+// faults stop and everything in flight has been delivered, both sides are
+// connected (if a frame was dropped, a single connect() retry is allowed,
+// since there are no retry timers in the model), and after each side sends
+// one more packet the sequence numbers on both sides match.
+//
+// Connecting normally clears both wires. Without that, a SYN1 or SYN2 left
+// over from an earlier handshake can complete a new one before the peer has
+// reset, and the peer's old user frames are then accepted into the new
+// session with mismatched sequence numbers. This is a limitation of the
+// protocol (the SYN frames carry nothing which identifies the handshake),
+// and test_stale_syn shows that the checker finds it.
+
+use core::hash::Hash;
+use std::collections::{HashSet, VecDeque};
+use std::vec::Vec;
+
+use crate::sim::split_frames;
+use crate::traits::Storage;
+use crate::vecstorage::VecStorage;
+use crate::{EndPoint, ParseResult};
+
+const PACKET_SIZE: usize = 8;
+const HISTORY_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Connect(usize),
+    // Both sides call connect() before either SYN0 is delivered.
+    ConnectBoth,
+    Send(usize),
+    Deliver(usize),
+    Drop(usize),
+}
+
+#[derive(Clone, Copy)]
+struct Bounds {
+    connects: u8,
+    sends: u8,
+    drops: u8,
+    // Whether connecting discards the frames in flight.
+    reset_line: bool,
+}
+
+#[derive(Clone)]
+struct Side {
+    endpoint: EndPoint,
+    storage: VecStorage,
+    // Frames sent by this side which haven't been delivered or dropped.
+    wire: VecDeque<Vec<u8>>,
+    next_counter: u8,
+    last_received: Option<u8>,
+    connects_left: u8,
+    sends_left: u8,
+}
+
+impl Side {
+    fn new(bounds: Bounds) -> Self {
+        Self {
+            endpoint: EndPoint::new(),
+            storage: VecStorage::new(PACKET_SIZE, HISTORY_LEN),
+            wire: VecDeque::new(),
+            next_counter: 0,
+            last_received: None,
+            connects_left: bounds.connects,
+            sends_left: bounds.sends,
+        }
+    }
+
+    fn flush(&mut self) {
+        let frames = split_frames(&self.storage.take_tx_data());
+        self.wire.extend(frames);
+    }
+
+    fn send(&mut self) {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        self.endpoint.write_packet(&[counter], &mut self.storage);
+    }
+
+    fn receive(&mut self, frame: &[u8]) {
+        for byte in frame.iter() {
+            if self.endpoint.parse_byte(*byte, &mut self.storage) == ParseResult::UserPacket {
+                let data = self.storage.rx_data();
+                assert_eq!(data.len(), 1, "corrupted packet {:?}", data);
+                let counter = data[0];
+                if let Some(last) = self.last_received {
+                    assert!(counter > last, "received {} after {}", counter, last);
+                }
+                self.last_received = Some(counter);
+            }
+        }
+    }
+
+    fn key(&mut self) -> impl Eq + Hash {
+        let tx = &self.endpoint.tx;
+        (
+            tx.connect_state as u8,
+            tx.rx_seq,
+            tx.tx_seq,
+            self.storage.tx_queue().len(),
+            self.next_counter,
+            self.last_received,
+            self.connects_left,
+            self.sends_left,
+            self.wire.clone(),
+        )
+    }
+}
+
+#[derive(Clone)]
+struct World {
+    sides: [Side; 2],
+    drops_left: u8,
+    reset_line: bool,
+    // Whether either side has called connect().
+    connecting: bool,
+    dropped: bool,
+}
+
+impl World {
+    fn new(bounds: Bounds) -> Self {
+        Self {
+            sides: [Side::new(bounds), Side::new(bounds)],
+            drops_left: bounds.drops,
+            reset_line: bounds.reset_line,
+            connecting: false,
+            dropped: false,
+        }
+    }
+
+    fn enabled(&self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if self.sides.iter().all(|side| side.connects_left > 0) {
+            actions.push(Action::ConnectBoth);
+        }
+        for (idx, side) in self.sides.iter().enumerate() {
+            if side.connects_left > 0 {
+                actions.push(Action::Connect(idx));
+            }
+            if side.sends_left > 0 && side.endpoint.is_connected() {
+                actions.push(Action::Send(idx));
+            }
+            if !side.wire.is_empty() {
+                actions.push(Action::Deliver(idx));
+                if self.drops_left > 0 {
+                    actions.push(Action::Drop(idx));
+                }
+            }
+        }
+        actions
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Connect(idx) => {
+                self.reset_line();
+                self.connect(idx);
+            }
+            Action::ConnectBoth => {
+                self.reset_line();
+                self.connect(0);
+                self.connect(1);
+            }
+            Action::Send(idx) => {
+                self.sides[idx].sends_left -= 1;
+                self.sides[idx].send();
+            }
+            Action::Deliver(idx) => self.deliver(idx),
+            Action::Drop(idx) => {
+                self.drops_left -= 1;
+                self.dropped = true;
+                self.sides[idx].wire.pop_front();
+            }
+        }
+        self.sides[0].flush();
+        self.sides[1].flush();
+    }
+
+    // Stale frames from the previous session could legitimately be accepted
+    // after a reconnect (the handshake carries no session identifier), so
+    // connecting models a line reset unless the bounds say otherwise.
+    fn reset_line(&mut self) {
+        if self.reset_line {
+            self.sides[0].wire.clear();
+            self.sides[1].wire.clear();
+        }
+    }
+
+    fn connect(&mut self, idx: usize) {
+        let side = &mut self.sides[idx];
+        side.connects_left -= 1;
+        side.endpoint.connect(&mut side.storage);
+        self.connecting = true;
+    }
+
+    // Delivers the frame at the head of sides[from].wire to the other side.
+    fn deliver(&mut self, from: usize) {
+        if let Some(frame) = self.sides[from].wire.pop_front() {
+            self.sides[1 - from].receive(&frame);
+        }
+    }
+
+    fn deliver_all(&mut self) {
+        while !(self.sides[0].wire.is_empty() && self.sides[1].wire.is_empty()) {
+            self.deliver(0);
+            self.deliver(1);
+            self.sides[0].flush();
+            self.sides[1].flush();
+        }
+    }
+
+    fn both_connected(&self) -> bool {
+        self.sides[0].endpoint.is_connected() && self.sides[1].endpoint.is_connected()
+    }
+
+    fn key(&mut self) -> impl Eq + Hash {
+        (
+            self.sides[0].key(),
+            self.sides[1].key(),
+            self.drops_left,
+            self.connecting,
+            self.dropped,
+        )
+    }
+
+    // Stops injecting faults and checks that the endpoints converge.
+    fn check_converges(mut self, trace: &[Action]) {
+        if !self.connecting {
+            return;
+        }
+        self.deliver_all();
+        if !self.both_connected() {
+            assert!(self.dropped, "not connected without faults: {:?}", trace);
+            let side = &mut self.sides[0];
+            side.endpoint.connect(&mut side.storage);
+            self.sides[0].flush();
+            self.deliver_all();
+        }
+        assert!(
+            self.both_connected(),
+            "not connected after retry: {:?}",
+            trace
+        );
+
+        // Lost packets at the tail are only recovered by later traffic.
+        for idx in 0..2 {
+            self.sides[idx].send();
+            self.sides[idx].flush();
+        }
+        self.deliver_all();
+        assert!(
+            self.both_connected(),
+            "disconnected by traffic: {:?}",
+            trace
+        );
+        for idx in 0..2 {
+            let tx = &self.sides[idx].endpoint.tx;
+            let peer = &self.sides[1 - idx];
+            assert_eq!(
+                tx.tx_seq, peer.endpoint.tx.rx_seq,
+                "sequence mismatch: {:?}",
+                trace
+            );
+            assert_eq!(
+                peer.last_received,
+                Some(self.sides[idx].next_counter - 1),
+                "last packet not delivered: {:?}",
+                trace
+            );
+        }
+    }
+}
+
+// Explores every state reachable within the bounds, returning the number of
+// distinct states.
+fn explore(bounds: Bounds) -> usize {
+    let mut visited = HashSet::new();
+    let mut stack = vec![(World::new(bounds), Vec::new())];
+    while let Some((mut world, trace)) = stack.pop() {
+        if !visited.insert(world.key()) {
+            continue;
+        }
+        world.clone().check_converges(&trace);
+        for action in world.enabled() {
+            let mut next = world.clone();
+            next.apply(action);
+            let mut next_trace = trace.clone();
+            next_trace.push(action);
+            stack.push((next, next_trace));
+        }
+    }
+    visited.len()
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[test]
+fn test_connect_interleavings() {
+    let states = explore(Bounds {
+        connects: 2,
+        sends: 0,
+        drops: 0,
+        reset_line: true,
+    });
+    assert!(states > 50);
+}
+
+#[test]
+fn test_connect_with_loss() {
+    explore(Bounds {
+        connects: 2,
+        sends: 0,
+        drops: 2,
+        reset_line: true,
+    });
+}
+
+#[test]
+fn test_traffic_with_loss() {
+    explore(Bounds {
+        connects: 2,
+        sends: 2,
+        drops: 1,
+        reset_line: true,
+    });
+}
+
+// Frames left over from an abandoned handshake are still on the wire when
+// the next one starts. This documents a known limitation rather than the
+// intended behaviour.
+#[test]
+#[should_panic(expected = "last packet not delivered")]
+fn test_stale_syn() {
+    explore(Bounds {
+        connects: 2,
+        sends: 1,
+        drops: 0,
+        reset_line: false,
+    });
+}
@@ -0,0 +1,270 @@
+use generic_array::{ArrayLength, GenericArray};
+use log::warn;
+
+use crate::traits::Storage;
+use crate::{EndPoint, ParseResult};
+
+/// Identifies a logical channel. The channel id is sent as the first byte of
+/// the payload of each user packet.
+pub type ChannelId = u8;
+
+/// Implemented by the application for each channel that it opens.
+pub trait ChannelHandler {
+    /// Called when a user packet has been received for the channel. `data`
+    /// contains the payload with the channel id removed.
+    fn handle_packet(&mut self, data: &[u8]);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MuxError {
+    /// The channel id is larger than the number of channels supported by the Mux.
+    InvalidChannel,
+    /// The channel has already been opened.
+    ChannelInUse,
+    /// The channel hasn't been opened.
+    ChannelNotOpen,
+}
+
+/// A Mux allows multiple logical channels to share a single EndPoint. Each
+/// user packet is prefixed with the id of the channel that it belongs to and
+/// received packets are dispatched to the handler registered for that channel.
+///
+/// N determines the number of channels, which are numbered 0 thru N - 1.
+pub struct Mux<'a, N: ArrayLength> {
+    endpoint: EndPoint,
+    channels: GenericArray<Option<&'a mut dyn ChannelHandler>, N>,
+}
+
+impl<'a, N: ArrayLength> Default for Mux<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, N: ArrayLength> Mux<'a, N> {
+    pub fn new() -> Self {
+        Self {
+            endpoint: EndPoint::new(),
+            channels: Default::default(),
+        }
+    }
+
+    /// Returns a reference to the underlying EndPoint.
+    pub fn endpoint(&self) -> &EndPoint {
+        &self.endpoint
+    }
+
+    /// Returns a mutable reference to the underlying EndPoint.
+    pub fn endpoint_mut(&mut self) -> &mut EndPoint {
+        &mut self.endpoint
+    }
+
+    pub fn connect(&mut self, storage: &mut dyn Storage) {
+        self.endpoint.connect(storage);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.endpoint.is_connected()
+    }
+
+    /// Returns the maximum number of channels supported by the Mux.
+    pub fn capacity(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Determines if the indicated channel is currently open.
+    pub fn is_open(&self, id: ChannelId) -> bool {
+        match self.channels.get(id as usize) {
+            Some(channel) => channel.is_some(),
+            None => false,
+        }
+    }
+
+    /// Opens a channel. Packets received for the channel will be passed to
+    /// `handler`.
+    pub fn open(
+        &mut self,
+        id: ChannelId,
+        handler: &'a mut dyn ChannelHandler,
+    ) -> Result<(), MuxError> {
+        let channel = self
+            .channels
+            .get_mut(id as usize)
+            .ok_or(MuxError::InvalidChannel)?;
+        if channel.is_some() {
+            return Err(MuxError::ChannelInUse);
+        }
+        *channel = Some(handler);
+        Ok(())
+    }
+
+    /// Closes a channel, returning the handler which was registered for it.
+    pub fn close(&mut self, id: ChannelId) -> Result<&'a mut dyn ChannelHandler, MuxError> {
+        let channel = self
+            .channels
+            .get_mut(id as usize)
+            .ok_or(MuxError::InvalidChannel)?;
+        channel.take().ok_or(MuxError::ChannelNotOpen)
+    }
+
+    /// Sends `data` as a user packet on the indicated channel.
+    pub fn write_packet(
+        &mut self,
+        id: ChannelId,
+        data: &[u8],
+        storage: &mut dyn Storage,
+    ) -> Result<(), MuxError> {
+        if id as usize >= self.capacity() {
+            return Err(MuxError::InvalidChannel);
+        }
+        if !self.is_open(id) {
+            return Err(MuxError::ChannelNotOpen);
+        }
+        self.endpoint.write_packet_vectored(&[&[id], data], storage);
+        Ok(())
+    }
+
+    /// Feeds a single byte into the underlying EndPoint. When a user packet
+    /// is received it's passed to the handler for its channel and UserPacket
+    /// is returned. Packets for channels which aren't open are dropped.
+    pub fn parse_byte(&mut self, byte: u8, storage: &mut dyn Storage) -> ParseResult {
+        let parse_result = self.endpoint.parse_byte(byte, storage);
+        if parse_result != ParseResult::UserPacket {
+            return parse_result;
+        }
+
+        let data = storage.rx_buf().data();
+        if data.is_empty() {
+            warn!("Dropping packet with no channel id");
+            return ParseResult::MoreDataNeeded;
+        }
+        let id = data[0];
+        match self.channels.get_mut(id as usize) {
+            Some(Some(handler)) => {
+                handler.handle_packet(&data[1..]);
+                ParseResult::UserPacket
+            }
+            _ => {
+                warn!("Dropping packet for channel {} which isn't open", id);
+                ParseResult::MoreDataNeeded
+            }
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{connect_endpoints, setup_log, TestStorage};
+    use std::vec::Vec;
+    use typenum::U4;
+
+    #[derive(Default)]
+    struct TestChannel {
+        packets: Vec<Vec<u8>>,
+    }
+
+    impl ChannelHandler for TestChannel {
+        fn handle_packet(&mut self, data: &[u8]) {
+            self.packets.push(data.to_vec());
+        }
+    }
+
+    fn parse_bytes<N: ArrayLength>(
+        mux: &mut Mux<N>,
+        bytes: &[u8],
+        storage: &mut TestStorage,
+    ) -> ParseResult {
+        let mut result = ParseResult::MoreDataNeeded;
+        for byte in bytes.iter() {
+            let parse_result = mux.parse_byte(*byte, storage);
+            if parse_result != ParseResult::MoreDataNeeded {
+                result = parse_result;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_open_close() {
+        setup_log();
+        let mut chan0 = TestChannel::default();
+        let mut chan1 = TestChannel::default();
+        let mut chan2 = TestChannel::default();
+        let mut chan3 = TestChannel::default();
+        let mut mux: Mux<U4> = Mux::new();
+
+        assert_eq!(mux.capacity(), 4);
+        assert!(mux.open(0, &mut chan0).is_ok());
+        assert_eq!(mux.open(0, &mut chan1), Err(MuxError::ChannelInUse));
+        assert_eq!(mux.open(4, &mut chan2), Err(MuxError::InvalidChannel));
+        assert!(mux.is_open(0));
+        assert!(!mux.is_open(1));
+
+        assert!(mux.close(0).is_ok());
+        assert_eq!(mux.close(0).err(), Some(MuxError::ChannelNotOpen));
+        assert_eq!(mux.close(4).err(), Some(MuxError::InvalidChannel));
+        assert!(!mux.is_open(0));
+        assert!(mux.open(0, &mut chan3).is_ok());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        setup_log();
+        let mut storage1 = TestStorage::new();
+        let mut storage2 = TestStorage::new();
+        let mut ep1 = EndPoint::new();
+
+        let mut chan1 = TestChannel::default();
+        let mut chan3 = TestChannel::default();
+        {
+            let mut mux: Mux<U4> = Mux::new();
+            connect_endpoints(&mut ep1, &mut storage1, mux.endpoint_mut(), &mut storage2);
+            assert!(mux.is_connected());
+            mux.open(1, &mut chan1).unwrap();
+            mux.open(3, &mut chan3).unwrap();
+
+            // Packets sent from a Mux are prefixed with the channel id.
+            assert_eq!(
+                mux.write_packet(2, b"Closed", &mut storage2),
+                Err(MuxError::ChannelNotOpen)
+            );
+            mux.write_packet(3, b"Mux", &mut storage2).unwrap();
+            assert_eq!(
+                ep1.parse_packet(storage2.tx_data(), &mut storage1),
+                ParseResult::UserPacket
+            );
+            assert_eq!(storage1.rx_data(), b"\x03Mux");
+
+            // Received packets are dispatched to the channel in the prefix.
+            ep1.write_packet(b"\x01One", &mut storage1);
+            let tx = storage1.tx_vec();
+            assert_eq!(
+                parse_bytes(&mut mux, &tx, &mut storage2),
+                ParseResult::UserPacket
+            );
+            ep1.write_packet(b"\x03Three", &mut storage1);
+            let tx = storage1.tx_vec();
+            assert_eq!(
+                parse_bytes(&mut mux, &tx, &mut storage2),
+                ParseResult::UserPacket
+            );
+
+            // Packets for closed channels are dropped.
+            ep1.write_packet(b"\x02Two", &mut storage1);
+            let tx = storage1.tx_vec();
+            assert_eq!(
+                parse_bytes(&mut mux, &tx, &mut storage2),
+                ParseResult::MoreDataNeeded
+            );
+        }
+        assert_eq!(chan1.packets, vec![b"One".to_vec()]);
+        assert_eq!(chan3.packets, vec![b"Three".to_vec()]);
+    }
+}
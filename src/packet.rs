@@ -46,10 +46,17 @@ pub enum PacketTypeResult {
     MoreDataNeeded,
 }
 
+#[derive(Clone)]
 pub struct PacketParser {
     raw_parser: RawPacketParser,
 }
 
+impl Default for PacketParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PacketParser {
     pub fn new() -> Self {
         Self {
@@ -68,25 +75,36 @@ impl PacketParser {
     }
 
     fn get_frame_seq(&self, header: u8) -> u8 {
-        return header & SEQ_MASK;
+        header & SEQ_MASK
     }
 
     pub fn parse_byte(&mut self, byte: u8, rx_data: &mut dyn PacketBuffer) -> PacketTypeResult {
         let parse_result = self.raw_parser.parse_byte(byte, rx_data);
+        self.packet_type_result(parse_result)
+    }
+
+    /// Parses bytes until a frame is complete (or aborted, etc), using the
+    /// bulk scanner of the raw parser. Returns the number of bytes consumed
+    /// along with the result, which is MoreDataNeeded if all of the bytes
+    /// were consumed without completing a frame.
+    pub fn parse_bytes(
+        &mut self,
+        bytes: &[u8],
+        rx_data: &mut dyn PacketBuffer,
+    ) -> (usize, PacketTypeResult) {
+        let (consumed, parse_result) = self.raw_parser.parse_bytes(bytes, rx_data);
+        (consumed, self.packet_type_result(parse_result))
+    }
+
+    fn packet_type_result(&self, parse_result: RawParseResult) -> PacketTypeResult {
         match parse_result {
             RawParseResult::RawPacketReceived(header) => {
                 let frame_type = self.get_frame_type(header);
                 let seq = self.get_frame_seq(header);
                 match frame_type {
-                    FrameType::USR => {
-                        return PacketTypeResult::PacketReceived(PacketType::USR { seq });
-                    }
-                    FrameType::RTX => {
-                        return PacketTypeResult::PacketReceived(PacketType::RTX { seq });
-                    }
-                    FrameType::NAK => {
-                        return PacketTypeResult::PacketReceived(PacketType::NAK { seq });
-                    }
+                    FrameType::USR => PacketTypeResult::PacketReceived(PacketType::USR { seq }),
+                    FrameType::RTX => PacketTypeResult::PacketReceived(PacketType::RTX { seq }),
+                    FrameType::NAK => PacketTypeResult::PacketReceived(PacketType::NAK { seq }),
                     FrameType::SYN => {
                         if let Some(seq_syn) = SeqSyn::from_u8(seq) {
                             return match seq_syn {
@@ -98,7 +116,7 @@ impl PacketParser {
                                 }
                             };
                         }
-                        return PacketTypeResult::MoreDataNeeded;
+                        PacketTypeResult::MoreDataNeeded
                     }
                 }
             }
@@ -0,0 +1,405 @@
+//! Writes decoded frames to a pcapng file, so that captures can be inspected
+//! with Wireshark and friends.
+//!
+//! Each frame is stored as one packet with the `LINKTYPE_USER0` link type.
+//! The packet data starts with a pseudo-header, followed by the unescaped
+//! payload:
+//!
+//! | Offset | Size | Contents                                              |
+//! |--------|------|-------------------------------------------------------|
+//! | 0      | 1    | `Direction`                                           |
+//! | 1      | 1    | Status: 0 good, 1 CRC error, 2 aborted, 3 runt, 4 truncated |
+//! | 2      | 1    | Flags: bit 0 is set if the header byte is present     |
+//! | 3      | 1    | Header byte (frame type and sequence number)          |
+//! | 4      | 2    | Received CRC (little endian) for CRC errors, else 0   |
+//!
+//! The direction is also written to the `epb_flags` option of each packet.
+//! `wireshark/sfp.lua` is a dissector for this format, generated by
+//! `dissector()`.
+
+use core::fmt::Write as _;
+use core::time::Duration;
+use std::io::{self, Write};
+use std::string::String;
+use std::vec::Vec;
+
+use crate::decode::{Frame, FrameStatus};
+use crate::packet::{FrameType, SeqSyn, FRAME_TYPE_MASK, SEQ_MASK};
+
+/// The link type used for SFP frames (DLT_USER0).
+pub const LINKTYPE_USER0: u16 = 147;
+
+/// The length of the pseudo-header which precedes each payload.
+pub const PSEUDO_HEADER_LEN: usize = 6;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const FLAG_HEADER: u8 = 0x01;
+
+// Names of the status codes, indexed by the code.
+const STATUS_NAMES: [&str; 5] = ["Good", "CRC error", "Aborted", "Runt", "Truncated"];
+
+/// Which way a frame was travelling. The values match the inbound/outbound
+/// direction of the pcapng `epb_flags` option, with A to B as inbound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Unknown = 0,
+    AToB = 1,
+    BToA = 2,
+}
+
+// Names of the directions, indexed by the value.
+const DIRECTION_NAMES: [&str; 3] = ["Unknown", "A to B", "B to A"];
+
+fn status_code(status: FrameStatus) -> u8 {
+    match status {
+        FrameStatus::Good => 0,
+        FrameStatus::CrcError(_) => 1,
+        FrameStatus::Aborted => 2,
+        FrameStatus::TooSmall => 3,
+        FrameStatus::Truncated => 4,
+    }
+}
+
+/// Returns the packet data (pseudo-header and payload) stored for a frame.
+pub fn packet_data(frame: &Frame, direction: Direction) -> Vec<u8> {
+    let crc = match frame.status {
+        FrameStatus::CrcError(crc) => crc,
+        _ => 0,
+    };
+    let mut data = Vec::with_capacity(PSEUDO_HEADER_LEN + frame.payload.len());
+    data.push(direction as u8);
+    data.push(status_code(frame.status));
+    data.push(if frame.header.is_some() {
+        FLAG_HEADER
+    } else {
+        0
+    });
+    data.push(frame.header.unwrap_or(0));
+    data.extend_from_slice(&crc.to_le_bytes());
+    data.extend_from_slice(&frame.payload);
+    data
+}
+
+/// Writes a pcapng file containing a single interface, with one packet per
+/// frame.
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and interface description blocks.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut pcapng = Self { writer };
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // The section length isn't known up front.
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        pcapng.write_block(BLOCK_SHB, &shb)?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snap length limit. Timestamps use the default resolution of
+        // microseconds.
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        pcapng.write_block(BLOCK_IDB, &idb)?;
+
+        Ok(pcapng)
+    }
+
+    /// Writes a frame. `timestamp` is the time since the Unix epoch at which
+    /// the frame was seen.
+    pub fn write_frame(
+        &mut self,
+        frame: &Frame,
+        direction: Direction,
+        timestamp: Duration,
+    ) -> io::Result<()> {
+        let data = packet_data(frame, direction);
+        let micros = timestamp.as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(data.len() + 40);
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&data);
+        pad(&mut epb);
+        if direction != Direction::Unknown {
+            epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+            epb.extend_from_slice(&4u16.to_le_bytes());
+            epb.extend_from_slice(&(direction as u32).to_le_bytes());
+            epb.extend_from_slice(&OPT_ENDOFOPT.to_le_bytes());
+            epb.extend_from_slice(&0u16.to_le_bytes());
+        }
+        self.write_block(BLOCK_EPB, &epb)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    // Writes a block, whose body must already be padded to 32 bits.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let len = (body.len() + 12) as u32;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&len.to_le_bytes())
+    }
+}
+
+fn pad(data: &mut Vec<u8>) {
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+}
+
+// Formats a Lua table mapping values to names.
+fn lua_table(entries: &[(u8, &str)]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|(value, name)| std::format!("[0x{:02x}] = \"{}\"", value, name))
+        .collect();
+    std::format!("{{ {} }}", entries.join(", "))
+}
+
+/// Generates a Wireshark Lua dissector for the packets written by
+/// `PcapngWriter`.
+pub fn dissector() -> String {
+    let frame_types: Vec<(u8, String)> = FrameType::VARIANTS
+        .iter()
+        .map(|frame_type| (*frame_type as u8, std::format!("{:?}", frame_type)))
+        .collect();
+    let syn_codes: Vec<(u8, String)> = SeqSyn::VARIANTS
+        .iter()
+        .map(|syn| (*syn as u8, std::format!("{:?}", syn)))
+        .collect();
+    let as_refs = |entries: &[(u8, String)]| -> String {
+        let entries: Vec<(u8, &str)> = entries
+            .iter()
+            .map(|(value, name)| (*value, name.as_str()))
+            .collect();
+        lua_table(&entries)
+    };
+    let numbered = |names: &[&'static str]| -> String {
+        let entries: Vec<(u8, &str)> = names
+            .iter()
+            .enumerate()
+            .map(|(value, name)| (value as u8, *name))
+            .collect();
+        lua_table(&entries)
+    };
+
+    let mut lua = String::new();
+    let _ = write!(
+        lua,
+        r#"-- Wireshark dissector for SFP frames written by serial_framing_protocol::pcapng
+-- (LINKTYPE_USER0). Generated by `sfp-decode --dissector`; do not edit.
+--
+-- Install by copying to the Wireshark personal plugins directory.
+
+local sfp = Proto("sfp", "Serial Framing Protocol")
+
+local FRAME_TYPE_MASK = 0x{frame_type_mask:02x}
+local SEQ_MASK = 0x{seq_mask:02x}
+local SYN = 0x{syn:02x}
+local CRC_ERROR = 1
+local FLAG_HEADER = 0x{flag_header:02x}
+local PSEUDO_HEADER_LEN = {pseudo_header_len}
+
+local directions = {directions}
+local statuses = {statuses}
+local frame_types = {frame_types}
+local syn_codes = {syn_codes}
+
+local f = sfp.fields
+f.direction = ProtoField.uint8("sfp.direction", "Direction", base.DEC, directions)
+f.status = ProtoField.uint8("sfp.status", "Status", base.DEC, statuses)
+f.header = ProtoField.uint8("sfp.header", "Header", base.HEX)
+f.frame_type = ProtoField.uint8("sfp.type", "Frame type", base.HEX, frame_types, FRAME_TYPE_MASK)
+f.seq = ProtoField.uint8("sfp.seq", "Sequence number", base.DEC, nil, SEQ_MASK)
+f.syn = ProtoField.uint8("sfp.syn", "SYN code", base.DEC, syn_codes, SEQ_MASK)
+f.crc = ProtoField.uint16("sfp.crc", "Received CRC", base.HEX)
+f.payload = ProtoField.bytes("sfp.payload", "Payload")
+
+function sfp.dissector(buf, pinfo, tree)
+    if buf:len() < PSEUDO_HEADER_LEN then
+        return 0
+    end
+    pinfo.cols.protocol = "SFP"
+    local t = tree:add(sfp, buf())
+    t:add(f.direction, buf(0, 1))
+    t:add(f.status, buf(1, 1))
+
+    local info = "---"
+    if bit.band(buf(2, 1):uint(), FLAG_HEADER) ~= 0 then
+        local header = buf(3, 1)
+        local frame_type = bit.band(header:uint(), FRAME_TYPE_MASK)
+        local seq = bit.band(header:uint(), SEQ_MASK)
+        t:add(f.header, header)
+        t:add(f.frame_type, header)
+        if frame_type == SYN then
+            t:add(f.syn, header)
+            info = syn_codes[seq] or ("SYN code=" .. seq)
+        else
+            t:add(f.seq, header)
+            info = frame_types[frame_type] .. " seq=" .. seq
+        end
+    end
+
+    local len = buf:len() - PSEUDO_HEADER_LEN
+    if len > 0 then
+        t:add(f.payload, buf(PSEUDO_HEADER_LEN))
+        info = info .. " len=" .. len
+    end
+    local status = buf(1, 1):uint()
+    if status == CRC_ERROR then
+        t:add_le(f.crc, buf(4, 2))
+        info = info .. string.format(" CRC error (received 0x%04x)", buf(4, 2):le_uint())
+    elseif status ~= 0 then
+        info = info .. " " .. (statuses[status] or "status " .. status)
+    end
+    pinfo.cols.info = info
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, sfp)
+"#,
+        frame_type_mask = FRAME_TYPE_MASK,
+        seq_mask = SEQ_MASK,
+        syn = FrameType::SYN as u8,
+        flag_header = FLAG_HEADER,
+        pseudo_header_len = PSEUDO_HEADER_LEN,
+        directions = numbered(&DIRECTION_NAMES),
+        statuses = numbered(&STATUS_NAMES),
+        frame_types = as_refs(&frame_types),
+        syn_codes = as_refs(&syn_codes),
+    );
+    lua
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::Decoder;
+    use crate::traits::{PacketWriter, SOF};
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    }
+
+    // Splits a pcapng file into (block type, body) pairs, checking the
+    // lengths along the way.
+    fn blocks(bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let len = u32_at(bytes, offset + 4) as usize;
+            assert!(len.is_multiple_of(4));
+            assert_eq!(u32_at(bytes, offset + len - 4) as usize, len);
+            blocks.push((u32_at(bytes, offset), &bytes[offset + 8..offset + len - 4]));
+            offset += len;
+        }
+        assert_eq!(offset, bytes.len());
+        blocks
+    }
+
+    #[test]
+    fn test_write_frames() {
+        let mut stream = Vec::new();
+        stream.write_packet_data(0xc1, &[]);
+        stream.write_packet_data(0x05, b"Hi\x7e");
+        stream.extend(&[SOF, 0x41, 0x42, 0x43, 0x44, 0x45, SOF]);
+        let frames = Decoder::new().decode(&stream);
+        assert_eq!(frames.len(), 3);
+
+        let mut pcapng = PcapngWriter::new(Vec::new()).unwrap();
+        let timestamp = Duration::new(1_700_000_000, 123_456_000);
+        pcapng
+            .write_frame(&frames[0], Direction::AToB, timestamp)
+            .unwrap();
+        pcapng
+            .write_frame(&frames[1], Direction::BToA, timestamp)
+            .unwrap();
+        pcapng
+            .write_frame(&frames[2], Direction::Unknown, timestamp)
+            .unwrap();
+        let bytes = pcapng.into_inner();
+
+        let blocks = blocks(&bytes);
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(
+            types,
+            vec![BLOCK_SHB, BLOCK_IDB, BLOCK_EPB, BLOCK_EPB, BLOCK_EPB]
+        );
+        assert_eq!(u32_at(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_USER0.to_le_bytes());
+
+        let micros = 1_700_000_000_123_456u64;
+        let epb = blocks[2].1;
+        assert_eq!(u32_at(epb, 4), (micros >> 32) as u32);
+        assert_eq!(u32_at(epb, 8), micros as u32);
+        assert_eq!(u32_at(epb, 12), 6);
+        assert_eq!(&epb[20..26], &[1, 0, 1, 0xc1, 0, 0]);
+        // Followed by the epb_flags option and the end of the options.
+        assert_eq!(&epb[28..], &[2, 0, 4, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+        let epb = blocks[3].1;
+        assert_eq!(u32_at(epb, 12), 9);
+        assert_eq!(&epb[20..29], b"\x02\x00\x01\x05\x00\x00Hi\x7e");
+        assert_eq!(u32_at(&epb[32..], 4), Direction::BToA as u32);
+
+        // The CRC error has no direction, so no options.
+        let epb = blocks[4].1;
+        let crc = match frames[2].status {
+            FrameStatus::CrcError(crc) => crc,
+            status => panic!("unexpected {:?}", status),
+        };
+        assert_eq!(u32_at(epb, 12), 8);
+        assert_eq!(&epb[20..24], &[0, 1, 1, 0x41]);
+        assert_eq!(&epb[24..26], &crc.to_le_bytes());
+        assert_eq!(&epb[26..28], b"BC");
+        assert_eq!(epb.len(), 28);
+    }
+
+    #[test]
+    fn test_packet_data_no_header() {
+        let frames = Decoder::new().decode(&[SOF, 0x7d, SOF]);
+        assert_eq!(
+            packet_data(&frames[0], Direction::Unknown),
+            vec![0, 2, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_dissector_up_to_date() {
+        // Regenerate with `cargo run --features cli --bin sfp-decode -- --dissector`.
+        assert_eq!(dissector(), include_str!("../wireshark/sfp.lua"));
+    }
+}
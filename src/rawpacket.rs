@@ -1,17 +1,17 @@
-use crate::crc::{Crc, CrcAccum, CRC_GOOD};
+use crate::crc::{Crc, CrcAccum, CRC_GOOD, CRC_LEN};
 
-use core::mem::size_of;
 use log::info;
+use memchr::memchr2;
 
 use crate::traits::{PacketBuffer, ESC, ESC_FLIP, SOF};
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 enum EscapeState {
     Normal,
     Escaping,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 enum FrameState {
     New,
     Collecting,
@@ -34,6 +34,7 @@ pub enum RawParseResult {
 //
 // So a packet will look like something like the following:
 // SOF HEADER ...data... CRC-LSB CRC-MSB SOF
+#[derive(Clone)]
 pub struct RawPacketParser {
     header: u8,
     crc: Crc,
@@ -41,7 +42,13 @@ pub struct RawPacketParser {
     frame_state: FrameState,
 }
 
-impl<'a> RawPacketParser {
+impl Default for RawPacketParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawPacketParser {
     pub fn new() -> Self {
         RawPacketParser {
             header: 0,
@@ -84,7 +91,7 @@ impl<'a> RawPacketParser {
                 // We've got a raw frame.
                 self.frame_state = FrameState::New;
 
-                if rx_data.len() < size_of::<CrcAccum>() {
+                if rx_data.len() < CRC_LEN {
                     return RawParseResult::PacketTooSmall;
                 }
 
@@ -120,6 +127,44 @@ impl<'a> RawPacketParser {
         RawParseResult::MoreDataNeeded
     }
 
+    /// Feeds bytes into the raw packet parser until something other than
+    /// MoreDataNeeded is returned, or all of the bytes have been consumed.
+    /// Returns the number of bytes consumed along with the result of the
+    /// last byte parsed.
+    ///
+    /// Runs of payload bytes which don't need to be unescaped are copied
+    /// into the PacketBuffer in bulk, which is much faster than feeding
+    /// them in one at a time.
+    pub fn parse_bytes(
+        &mut self,
+        bytes: &[u8],
+        rx_data: &mut dyn PacketBuffer,
+    ) -> (usize, RawParseResult) {
+        let mut idx = 0;
+        while idx < bytes.len() {
+            if self.frame_state == FrameState::Collecting
+                && self.escape_state == EscapeState::Normal
+            {
+                let rest = &bytes[idx..];
+                let run = &rest[..memchr2(SOF, ESC, rest).unwrap_or(rest.len())];
+                // If the run doesn't fit, then let parse_byte deal with the
+                // overflow.
+                if !run.is_empty() && run.len() <= rx_data.capacity() - rx_data.len() {
+                    rx_data.append_data(run);
+                    self.crc.accum_bytes(run);
+                    idx += run.len();
+                    continue;
+                }
+            }
+            let result = self.parse_byte(bytes[idx], rx_data);
+            idx += 1;
+            if result != RawParseResult::MoreDataNeeded {
+                return (idx, result);
+            }
+        }
+        (idx, RawParseResult::MoreDataNeeded)
+    }
+
     pub fn reset(&mut self) {
         self.crc.reset();
         self.escape_state = EscapeState::Normal;
@@ -137,8 +182,10 @@ mod tests {
     use super::*;
     use crate::testutils::{parse_bytes, parse_bytes_as_packet, setup_log, TestPacketBuffer};
     use crate::traits::PacketWriter;
+    use crate::vecstorage::VecPacketBuffer;
     use log::info;
     use pretty_hex::*;
+    use proptest::prelude::*;
     use std::vec::Vec;
 
     fn encode_decode_packet(parser: &mut RawPacketParser, header: u8, data: &[u8]) -> Vec<u8> {
@@ -154,7 +201,7 @@ mod tests {
 
         // Then run the generated packet through the packet parser
         let mut rx_data = TestPacketBuffer::new();
-        let ret = parse_bytes_as_packet(parser, &writer.data(), &mut rx_data);
+        let ret = parse_bytes_as_packet(parser, writer.data(), &mut rx_data);
         info!("=== Reparsed ===");
         info!("{:?}", &ret.hex_dump());
         ret
@@ -172,7 +219,7 @@ mod tests {
         info!("=== Output ===");
         info!("{:?}", (&writer.data()).hex_dump());
 
-        (&writer.data()).to_vec()
+        writer.data().to_vec()
     }
 
     #[test]
@@ -182,7 +229,7 @@ mod tests {
         let mut parser = RawPacketParser::new();
 
         // Cover every type of return code from the parser
-        let tests = &vec![
+        let tests = &[
             (vec![SOF, SOF], RawParseResult::MoreDataNeeded),
             (vec![SOF, 0x00, SOF], RawParseResult::PacketTooSmall),
             (vec![SOF, 0x00, 0x00, SOF], RawParseResult::PacketTooSmall),
@@ -221,7 +268,7 @@ mod tests {
 
         // NOTE: packets with a header of 0xcc won't be run through the writer test since those tests
         //       don't regenerate the input.
-        let tests = &vec![
+        let tests = &[
             // Plain unescaped packet
             (
                 vec![SOF, 0xc0, 0x11, 0x5e, 0xe4, 0xfb, SOF],
@@ -284,7 +331,7 @@ mod tests {
         // Take each of the folloing "user packets", write them out, and then
         // reparse to make sure that we get the original packets back.
 
-        let tests = &vec![
+        let tests = &[
             vec![0xc0],
             vec![0xc0, 0x11],
             vec![0xc0, 0x11, 0x22],
@@ -303,4 +350,131 @@ mod tests {
             assert_eq!(&encode_decode_packet(&mut parser, header, data), test);
         }
     }
+
+    #[test]
+    fn test_parse_bytes() {
+        setup_log();
+
+        // A stream with escaped bytes, an aborted frame, a runt, a frame
+        // which is too big for the buffer, and a CRC error.
+        let mut stream = Vec::new();
+        stream.write_packet_data(0xc0, &[]);
+        stream.write_packet_data(0x01, b"Hello World");
+        stream.write_packet_data(0x02, &[SOF, ESC, 0x11, SOF, 0x22, ESC]);
+        stream.extend_from_slice(&[SOF, 0x03, 0x11, ESC, SOF]);
+        stream.extend_from_slice(&[SOF, 0x04, SOF]);
+        stream.write_packet_data(0x05, &[0x33; 20]);
+        stream.extend_from_slice(&[SOF, 0x06, 0x11, 0x22, 0x33, SOF]);
+        stream.write_packet_data(0x07, b"Done");
+
+        let mut expected = Vec::new();
+        let mut parser = RawPacketParser::new();
+        let mut rx_data = VecPacketBuffer::new(16);
+        for byte in stream.iter() {
+            let result = parser.parse_byte(*byte, &mut rx_data);
+            if result != RawParseResult::MoreDataNeeded {
+                expected.push((result, rx_data.data().to_vec()));
+            }
+        }
+        assert_eq!(expected.len(), 8);
+
+        // Feeding the stream in chunks of various sizes gives the same
+        // results as feeding it in a byte at a time.
+        for chunk_size in 1..stream.len() {
+            let mut results = Vec::new();
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(16);
+            for chunk in stream.chunks(chunk_size) {
+                let mut idx = 0;
+                while idx < chunk.len() {
+                    let (consumed, result) = parser.parse_bytes(&chunk[idx..], &mut rx_data);
+                    idx += consumed;
+                    if result != RawParseResult::MoreDataNeeded {
+                        results.push((result, rx_data.data().to_vec()));
+                    }
+                }
+            }
+            assert_eq!(results, expected);
+        }
+    }
+
+    // Feeds `bytes` through parse_byte, checking that the buffer never
+    // overflows, and returns every result other than MoreDataNeeded along
+    // with the buffer contents at that point.
+    fn parse_all(bytes: &[u8], capacity: usize) -> Vec<(RawParseResult, Vec<u8>)> {
+        let mut parser = RawPacketParser::new();
+        let mut rx_data = VecPacketBuffer::new(capacity);
+        let mut results = Vec::new();
+        for byte in bytes.iter() {
+            let result = parser.parse_byte(*byte, &mut rx_data);
+            assert!(rx_data.len() <= capacity);
+            if result != RawParseResult::MoreDataNeeded {
+                results.push((result, rx_data.data().to_vec()));
+            }
+        }
+        results
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(header: u8, payload in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let mut frame = Vec::new();
+            frame.write_packet_data(header, &payload);
+
+            let results = parse_all(&frame, payload.len() + 2);
+            prop_assert_eq!(results, vec![(RawParseResult::RawPacketReceived(header), payload.clone())]);
+
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(payload.len() + 2);
+            let (consumed, result) = parser.parse_bytes(&frame, &mut rx_data);
+            prop_assert_eq!(consumed, frame.len());
+            prop_assert_eq!(result, RawParseResult::RawPacketReceived(header));
+            prop_assert_eq!(rx_data.data(), &payload[..]);
+        }
+
+        #[test]
+        fn prop_garbage(
+            garbage in proptest::collection::vec(any::<u8>(), 0..1000),
+            capacity in 0usize..40,
+            chunk_size in 1usize..64,
+        ) {
+            let expected = parse_all(&garbage, capacity);
+
+            // The bulk parser never overflows the buffer either, and agrees
+            // with the byte at a time parser.
+            let mut parser = RawPacketParser::new();
+            let mut rx_data = VecPacketBuffer::new(capacity);
+            let mut results = Vec::new();
+            for chunk in garbage.chunks(chunk_size) {
+                let mut idx = 0;
+                while idx < chunk.len() {
+                    let (consumed, result) = parser.parse_bytes(&chunk[idx..], &mut rx_data);
+                    prop_assert!(rx_data.len() <= capacity);
+                    idx += consumed;
+                    if result != RawParseResult::MoreDataNeeded {
+                        results.push((result, rx_data.data().to_vec()));
+                    }
+                }
+            }
+            prop_assert_eq!(results, expected);
+        }
+
+        #[test]
+        fn prop_resync(
+            garbage in proptest::collection::vec(any::<u8>(), 0..200),
+            header: u8,
+            payload in proptest::collection::vec(any::<u8>(), 0..40),
+        ) {
+            // A valid frame is always received after garbage, as long as
+            // the garbage is terminated by a SOF.
+            let mut bytes = garbage;
+            bytes.push(SOF);
+            bytes.write_packet_data(header, &payload);
+            let results = parse_all(&bytes, 42);
+            prop_assert_eq!(
+                results.last(),
+                Some(&(RawParseResult::RawPacketReceived(header), payload))
+            );
+        }
+    }
 }
@@ -0,0 +1,879 @@
+//! Records an EndPoint session to a compact binary log, and replays the log
+//! against a fresh EndPoint to check that it behaves the same way. This
+//! allows a failure seen in the field to be reproduced locally.
+//!
+//! The log starts with a header (`b"SFPR"`, a version byte, then the packet
+//! size, history length and receive buffer capacity as varints), followed
+//! by a stream of events. Each event is a tag byte followed by its fields,
+//! with lengths and ticks encoded as LEB128 varints.
+
+use core::fmt;
+use std::string::ToString;
+use std::vec::Vec;
+
+use crate::crc::CRC_LEN;
+use crate::decode::Decoder;
+use crate::packet::FrameType;
+use crate::traits::{PacketBuffer, PacketQueue, PacketWriter, Storage};
+use crate::vecstorage::VecStorage;
+use crate::{ConnectState, EndPoint, ParseResult, SEQ_INIT};
+
+const MAGIC: &[u8; 4] = b"SFPR";
+const VERSION: u8 = 2;
+
+// The largest storage which a replay will allocate. Anything bigger in a
+// log's header is taken to be corruption.
+const MAX_PACKET_SIZE: usize = 0x10000;
+const MAX_HISTORY_LEN: usize = 256;
+
+const TAG_CONNECT: u8 = 0;
+const TAG_WRITE: u8 = 1;
+const TAG_RECEIVED: u8 = 2;
+const TAG_TICK: u8 = 3;
+const TAG_TRANSMITTED: u8 = 4;
+const TAG_STATE: u8 = 5;
+const TAG_DELIVERED: u8 = 6;
+const TAG_REJECTED: u8 = 7;
+
+const REJECTED_ABORTED: u8 = 0;
+const REJECTED_TOO_SMALL: u8 = 1;
+const REJECTED_CRC_ERROR: u8 = 2;
+
+// The connection state of an EndPoint, as recorded in the log.
+c_like_enum! {
+  LinkState {
+    Disconnected = 0,
+    SentSyn0 = 1,
+    SentSyn1 = 2,
+    Connected = 3,
+  }
+}
+
+fn link_state(endpoint: &EndPoint) -> LinkState {
+    match endpoint.tx.connect_state {
+        ConnectState::Disconnected => LinkState::Disconnected,
+        ConnectState::SentSyn0 => LinkState::SentSyn0,
+        ConnectState::SentSyn1 => LinkState::SentSyn1,
+        ConnectState::Connected => LinkState::Connected,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LogError {
+    /// The log doesn't start with the expected magic bytes.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The storage sizes in the header are too large to replay.
+    BadHeader,
+    /// The log ends part way through an event.
+    Truncated,
+    UnknownEvent(u8),
+    UnknownState(u8),
+}
+
+/// Returned by `Recorder::new` when the EndPoint or its storage has already
+/// been used. A replay starts from a fresh EndPoint, so the recording has to
+/// as well.
+#[derive(Debug, PartialEq)]
+pub struct NotFresh;
+
+/// Something which happened during a session. `Connect`, `Write`, `Received`
+/// and `Tick` are the inputs which drive the EndPoint, and the rest are how
+/// it responded.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// `connect` was called.
+    Connect,
+    /// A user packet with this payload was sent.
+    Write(Vec<u8>),
+    /// A chunk of bytes was received.
+    Received(Vec<u8>),
+    /// The caller's clock, in whatever units the caller uses.
+    Tick(u64),
+    /// A frame was transmitted, as it appeared on the wire.
+    Transmitted(Vec<u8>),
+    /// The connection state changed.
+    State(LinkState),
+    /// A user packet was received and delivered.
+    Delivered(Vec<u8>),
+    /// A received frame was discarded.
+    Rejected(ParseResult),
+}
+
+impl Event {
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            Event::Connect | Event::Write(_) | Event::Received(_) | Event::Tick(_)
+        )
+    }
+
+    fn encode(&self, log: &mut Vec<u8>) {
+        match self {
+            Event::Connect => log.push(TAG_CONNECT),
+            Event::Write(data) => encode_bytes(log, TAG_WRITE, data),
+            Event::Received(data) => encode_bytes(log, TAG_RECEIVED, data),
+            Event::Tick(now) => {
+                log.push(TAG_TICK);
+                encode_varint(log, *now);
+            }
+            Event::Transmitted(frame) => encode_bytes(log, TAG_TRANSMITTED, frame),
+            Event::State(state) => log.extend_from_slice(&[TAG_STATE, *state as u8]),
+            Event::Delivered(data) => encode_bytes(log, TAG_DELIVERED, data),
+            Event::Rejected(result) => match result {
+                ParseResult::AbortedPacket => {
+                    log.extend_from_slice(&[TAG_REJECTED, REJECTED_ABORTED])
+                }
+                ParseResult::PacketTooSmall => {
+                    log.extend_from_slice(&[TAG_REJECTED, REJECTED_TOO_SMALL])
+                }
+                ParseResult::CrcError(crc) => {
+                    log.extend_from_slice(&[TAG_REJECTED, REJECTED_CRC_ERROR]);
+                    log.extend_from_slice(&crc.to_le_bytes());
+                }
+                // Only frames which were discarded are recorded.
+                ParseResult::UserPacket | ParseResult::MoreDataNeeded => unreachable!(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    /// Formats a one line summary of the event, e.g. "transmitted USR seq=3
+    /// len=5" or "state Connected".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Connect => write!(f, "connect"),
+            Event::Write(data) => write!(f, "write len={}", data.len()),
+            Event::Received(data) => write!(f, "received {} bytes", data.len()),
+            Event::Tick(now) => write!(f, "tick {}", now),
+            Event::Transmitted(frame) => {
+                let summary = Decoder::new()
+                    .decode(frame)
+                    .first()
+                    .map(|frame| frame.to_string())
+                    .unwrap_or_default();
+                write!(f, "transmitted {}", summary)
+            }
+            Event::State(state) => write!(f, "state {:?}", state),
+            Event::Delivered(data) => write!(f, "delivered len={}", data.len()),
+            Event::Rejected(ParseResult::CrcError(crc)) => {
+                write!(f, "rejected CRC error (received 0x{:04x})", crc)
+            }
+            Event::Rejected(result) => write!(f, "rejected {:?}", result),
+        }
+    }
+}
+
+fn encode_varint(log: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        log.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    log.push(value as u8);
+}
+
+fn encode_bytes(log: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    log.push(tag);
+    encode_varint(log, data.len() as u64);
+    log.extend_from_slice(data);
+}
+
+struct LogReader<'a> {
+    log: &'a [u8],
+}
+
+impl<'a> LogReader<'a> {
+    fn byte(&mut self) -> Result<u8, LogError> {
+        let (byte, rest) = self.log.split_first().ok_or(LogError::Truncated)?;
+        self.log = rest;
+        Ok(*byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, LogError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64).checked_shl(shift).unwrap_or(0);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, LogError> {
+        let len = self.varint()?;
+        if len > self.log.len() as u64 {
+            return Err(LogError::Truncated);
+        }
+        let (bytes, rest) = self.log.split_at(len as usize);
+        self.log = rest;
+        Ok(bytes.to_vec())
+    }
+
+    fn event(&mut self) -> Result<Event, LogError> {
+        Ok(match self.byte()? {
+            TAG_CONNECT => Event::Connect,
+            TAG_WRITE => Event::Write(self.bytes()?),
+            TAG_RECEIVED => Event::Received(self.bytes()?),
+            TAG_TICK => Event::Tick(self.varint()?),
+            TAG_TRANSMITTED => Event::Transmitted(self.bytes()?),
+            TAG_STATE => {
+                let state = self.byte()?;
+                Event::State(LinkState::from_u8(state).ok_or(LogError::UnknownState(state))?)
+            }
+            TAG_DELIVERED => Event::Delivered(self.bytes()?),
+            TAG_REJECTED => Event::Rejected(match self.byte()? {
+                REJECTED_ABORTED => ParseResult::AbortedPacket,
+                REJECTED_TOO_SMALL => ParseResult::PacketTooSmall,
+                REJECTED_CRC_ERROR => {
+                    ParseResult::CrcError(u16::from_le_bytes([self.byte()?, self.byte()?]))
+                }
+                code => return Err(LogError::UnknownEvent(code)),
+            }),
+            tag => return Err(LogError::UnknownEvent(tag)),
+        })
+    }
+}
+
+/// The storage sizes which the session was recorded with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub packet_size: usize,
+    pub history_len: usize,
+    /// The capacity of the receive buffer, including the CRC.
+    pub rx_capacity: usize,
+}
+
+/// Splits a log into its header and events.
+pub fn parse_log(log: &[u8]) -> Result<(Header, Vec<Event>), LogError> {
+    if !log.starts_with(MAGIC) {
+        return Err(LogError::BadMagic);
+    }
+    let mut reader = LogReader {
+        log: &log[MAGIC.len()..],
+    };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(LogError::UnsupportedVersion(version));
+    }
+    let mut size = |max: usize| match reader.varint()? {
+        size if size <= max as u64 => Ok(size as usize),
+        _ => Err(LogError::BadHeader),
+    };
+    let header = Header {
+        packet_size: size(MAX_PACKET_SIZE)?,
+        history_len: size(MAX_HISTORY_LEN)?,
+        rx_capacity: size(MAX_PACKET_SIZE + CRC_LEN)?,
+    };
+    let mut events = Vec::new();
+    while !reader.log.is_empty() {
+        events.push(reader.event()?);
+    }
+    Ok((header, events))
+}
+
+/// Passes everything on to the caller's storage, while logging each frame
+/// written through it. Returned by `Recorder::storage`.
+pub struct RecordingStorage<'a> {
+    inner: &'a mut dyn Storage,
+    recorder: &'a mut Recorder,
+}
+
+impl<'a> Storage for RecordingStorage<'a> {
+    fn rx_buf(&mut self) -> &mut dyn PacketBuffer {
+        self.inner.rx_buf()
+    }
+
+    fn tx_writer(&mut self) -> &mut dyn PacketWriter {
+        self
+    }
+
+    fn tx_queue(&mut self) -> &mut dyn PacketQueue {
+        self.inner.tx_queue()
+    }
+}
+
+impl<'a> PacketWriter for RecordingStorage<'a> {
+    fn start_write(&mut self) {
+        self.recorder.frame.clear();
+        self.inner.tx_writer().start_write();
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.write_bytes(&[byte]);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.recorder.frame.extend_from_slice(bytes);
+        self.inner.tx_writer().write_bytes(bytes);
+    }
+
+    fn end_write(&mut self) {
+        self.inner.tx_writer().end_write();
+        self.recorder.log_frame();
+    }
+}
+
+/// Records a session of an EndPoint owned by the caller, logging everything
+/// which goes in and out of it.
+///
+/// `connect`, `write_packet` and `parse_bytes` call the EndPoint and log
+/// the results. Code which calls the EndPoint itself (e.g. a `Mux`, `Rpc`
+/// or `BlobSender`) can be given the storage returned by `storage` instead,
+/// which logs each frame sent, and a `Write` event for each user packet.
+/// Connecting and received bytes then need to be logged using
+/// `record_connect`, `record_received` and `record_result`.
+///
+/// The log is kept in memory until it's removed by `take_log`, so a
+/// long running session needs to drain it regularly.
+pub struct Recorder {
+    log: Vec<u8>,
+    // The frame currently being written.
+    frame: Vec<u8>,
+    state: LinkState,
+}
+
+impl Recorder {
+    /// Creates a recorder for `endpoint`, which will use `storage`. The
+    /// sizes of the storage are recorded so that the replay can match them.
+    /// Fails if the EndPoint has connected, or anything has been sent using
+    /// the storage.
+    pub fn new(endpoint: &EndPoint, storage: &mut dyn Storage) -> Result<Self, NotFresh> {
+        let tx = &endpoint.tx;
+        let fresh = tx.connect_state == ConnectState::Disconnected
+            && tx.rx_seq == SEQ_INIT
+            && tx.tx_seq == SEQ_INIT
+            && storage.tx_queue().is_empty();
+        if !fresh {
+            return Err(NotFresh);
+        }
+        Ok(Self::with_storage(storage))
+    }
+
+    fn with_storage(storage: &mut dyn Storage) -> Self {
+        let rx_capacity = storage.rx_buf().capacity();
+        let tx_queue = storage.tx_queue();
+        let history_len = tx_queue.capacity();
+        let packet_size = tx_queue.packet(0).map_or(0, |packet| packet.capacity());
+
+        let mut log = Vec::new();
+        log.extend_from_slice(MAGIC);
+        log.push(VERSION);
+        encode_varint(&mut log, packet_size as u64);
+        encode_varint(&mut log, history_len as u64);
+        encode_varint(&mut log, rx_capacity as u64);
+        Self {
+            log,
+            frame: Vec::new(),
+            state: LinkState::Disconnected,
+        }
+    }
+
+    /// Returns the log recorded so far.
+    pub fn log(&self) -> &[u8] {
+        &self.log
+    }
+
+    /// Removes and returns the log recorded so far. The logs returned by
+    /// successive calls can be concatenated to form the complete log.
+    pub fn take_log(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.log)
+    }
+
+    /// Records the caller's clock. The EndPoint doesn't use it, but it
+    /// shows when each event happened.
+    pub fn tick(&mut self, now: u64) {
+        Event::Tick(now).encode(&mut self.log);
+    }
+
+    /// Wraps `storage` so that the frames written to it are logged.
+    pub fn storage<'a>(&'a mut self, storage: &'a mut dyn Storage) -> RecordingStorage<'a> {
+        RecordingStorage {
+            inner: storage,
+            recorder: self,
+        }
+    }
+
+    /// Logs that `connect` is about to be called.
+    pub fn record_connect(&mut self) {
+        Event::Connect.encode(&mut self.log);
+    }
+
+    /// Logs a chunk of received bytes which is about to be parsed.
+    pub fn record_received(&mut self, bytes: &[u8]) {
+        encode_bytes(&mut self.log, TAG_RECEIVED, bytes);
+    }
+
+    /// Logs a change in the EndPoint's connection state, and the result
+    /// returned by `parse_byte`. This should be called after each byte is
+    /// parsed, and after connecting (with `MoreDataNeeded`).
+    pub fn record_result(
+        &mut self,
+        endpoint: &EndPoint,
+        result: ParseResult,
+        storage: &mut dyn Storage,
+    ) {
+        let state = link_state(endpoint);
+        if state != self.state {
+            self.state = state;
+            Event::State(state).encode(&mut self.log);
+        }
+        match result {
+            ParseResult::MoreDataNeeded => {}
+            ParseResult::UserPacket => {
+                encode_bytes(&mut self.log, TAG_DELIVERED, storage.rx_buf().data())
+            }
+            result => Event::Rejected(result).encode(&mut self.log),
+        }
+    }
+
+    pub fn connect(&mut self, endpoint: &mut EndPoint, storage: &mut dyn Storage) {
+        self.record_connect();
+        endpoint.connect(&mut self.storage(storage));
+        self.record_result(endpoint, ParseResult::MoreDataNeeded, storage);
+    }
+
+    pub fn write_packet(
+        &mut self,
+        endpoint: &mut EndPoint,
+        data: &[u8],
+        storage: &mut dyn Storage,
+    ) {
+        endpoint.write_packet(data, &mut self.storage(storage));
+    }
+
+    /// Parses a chunk of received bytes, calling `f` with the result of
+    /// each frame. The packet is passed along with `UserPacket`, and is
+    /// empty for the other results.
+    pub fn parse_bytes(
+        &mut self,
+        endpoint: &mut EndPoint,
+        bytes: &[u8],
+        storage: &mut dyn Storage,
+        mut f: impl FnMut(ParseResult, &[u8]),
+    ) {
+        self.record_received(bytes);
+        for byte in bytes.iter() {
+            let result = endpoint.parse_byte(*byte, &mut self.storage(storage));
+            self.record_result(endpoint, result, storage);
+            match result {
+                ParseResult::MoreDataNeeded => {}
+                ParseResult::UserPacket => f(result, storage.rx_buf().data()),
+                result => f(result, &[]),
+            }
+        }
+    }
+
+    // Logs the frame which has just been written, preceded by a Write for
+    // a user packet.
+    fn log_frame(&mut self) {
+        let decoded = Decoder::with_capacity(self.frame.len()).decode(&self.frame);
+        if let Some(decoded) = decoded.first() {
+            if decoded.is_good() && decoded.frame_type() == Some(FrameType::USR) {
+                encode_bytes(&mut self.log, TAG_WRITE, &decoded.payload);
+            }
+        }
+        encode_bytes(&mut self.log, TAG_TRANSMITTED, &self.frame);
+    }
+}
+
+/// Where a replay first behaved differently from the recording.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// Index of the first event which differs. One of the event lists may
+    /// end at this index.
+    pub index: usize,
+    /// The most recent tick before the divergence.
+    pub tick: Option<u64>,
+}
+
+/// The recorded events, and the events produced by replaying their inputs.
+#[derive(Debug)]
+pub struct Replay {
+    pub header: Header,
+    pub recorded: Vec<Event>,
+    pub replayed: Vec<Event>,
+}
+
+impl Replay {
+    /// Returns the first difference between the recording and the replay,
+    /// if there is one.
+    pub fn divergence(&self) -> Option<Divergence> {
+        let len = self.recorded.len().max(self.replayed.len());
+        let index = (0..len).find(|idx| self.recorded.get(*idx) != self.replayed.get(*idx))?;
+        let tick = self.recorded[..index.min(self.recorded.len())]
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                Event::Tick(now) => Some(*now),
+                _ => None,
+            });
+        Some(Divergence { index, tick })
+    }
+}
+
+/// Drives a fresh EndPoint with the inputs from a log, recording how it
+/// responds.
+pub fn replay(log: &[u8]) -> Result<Replay, LogError> {
+    let (header, recorded) = parse_log(log)?;
+    let mut storage =
+        VecStorage::with_rx_capacity(header.packet_size, header.history_len, header.rx_capacity);
+    let mut recorder = Recorder::with_storage(&mut storage);
+    let mut endpoint = EndPoint::new();
+    for event in recorded.iter() {
+        match event {
+            Event::Connect => recorder.connect(&mut endpoint, &mut storage),
+            Event::Write(data) => recorder.write_packet(&mut endpoint, data, &mut storage),
+            Event::Received(data) => {
+                recorder.parse_bytes(&mut endpoint, data, &mut storage, |_, _| {})
+            }
+            Event::Tick(now) => recorder.tick(*now),
+            _ => {}
+        }
+    }
+    let (_, replayed) = parse_log(recorder.log())?;
+    Ok(Replay {
+        header,
+        recorded,
+        replayed,
+    })
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::{ChannelHandler, Mux};
+    use crate::traits::SOF;
+    use generic_array::typenum::U2;
+    use std::string::String;
+
+    // A recorded EndPoint connected to a plain one.
+    struct Session {
+        recorder: Recorder,
+        endpoint: EndPoint,
+        storage: VecStorage,
+        peer: EndPoint,
+        peer_storage: VecStorage,
+        delivered: Vec<Vec<u8>>,
+        tick: u64,
+    }
+
+    impl Session {
+        fn new() -> Self {
+            let mut storage = VecStorage::new(32, 4);
+            let endpoint = EndPoint::new();
+            Self {
+                recorder: Recorder::new(&endpoint, &mut storage).unwrap(),
+                endpoint,
+                storage,
+                peer: EndPoint::new(),
+                peer_storage: VecStorage::new(32, 4),
+                delivered: Vec::new(),
+                tick: 0,
+            }
+        }
+
+        // Passes the bytes sent by each side to the other, optionally
+        // corrupting the ones sent by the peer.
+        fn exchange(&mut self, corrupt: bool) {
+            self.tick += 10;
+            self.recorder.tick(self.tick);
+            for byte in self.storage.take_tx_data() {
+                self.peer.parse_byte(byte, &mut self.peer_storage);
+            }
+            let mut bytes = self.peer_storage.take_tx_data();
+            if corrupt {
+                let idx = bytes.len() - 2;
+                bytes[idx] ^= 0x01;
+            }
+            // Deliver the bytes in two chunks.
+            let (first, second) = bytes.split_at(bytes.len() / 2);
+            let delivered = &mut self.delivered;
+            for chunk in [first, second].iter() {
+                self.recorder.parse_bytes(
+                    &mut self.endpoint,
+                    chunk,
+                    &mut self.storage,
+                    |result, packet| {
+                        if result == ParseResult::UserPacket {
+                            delivered.push(packet.to_vec());
+                        }
+                    },
+                );
+            }
+        }
+    }
+
+    // Runs a session including a corrupted frame which is recovered by NAK
+    // and RTX. Returns the log.
+    fn record_session() -> Vec<u8> {
+        let mut session = Session::new();
+        session
+            .recorder
+            .connect(&mut session.endpoint, &mut session.storage);
+        session.exchange(false);
+        session.exchange(false);
+        assert!(session.endpoint.is_connected());
+
+        session
+            .recorder
+            .write_packet(&mut session.endpoint, b"Hello", &mut session.storage);
+        session.peer.write_packet(b"One", &mut session.peer_storage);
+        session.exchange(true);
+        session.peer.write_packet(b"Two", &mut session.peer_storage);
+        session.exchange(false);
+        session.exchange(false);
+
+        assert_eq!(session.delivered, vec![b"One".to_vec(), b"Two".to_vec()]);
+        session.recorder.take_log()
+    }
+
+    #[test]
+    fn test_record() {
+        let log = record_session();
+        let (header, events) = parse_log(&log).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                packet_size: 32,
+                history_len: 4,
+                rx_capacity: 34,
+            }
+        );
+
+        let summary: Vec<String> = events
+            .iter()
+            .filter(|event| !matches!(event, Event::Received(_) | Event::Tick(_)))
+            .map(|event| event.to_string())
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "connect",
+                "transmitted SYN0",
+                "state SentSyn0",
+                "transmitted SYN2",
+                "state Connected",
+                "write len=5",
+                "transmitted USR seq=0 len=5",
+                "rejected CRC error (received 0x7a81)",
+                "transmitted NAK seq=0",
+                "delivered len=3",
+                "delivered len=3",
+            ]
+        );
+        assert!(events[0].is_input());
+        assert!(!events[1].is_input());
+    }
+
+    #[test]
+    fn test_replay() {
+        let log = record_session();
+        let replay = replay(&log).unwrap();
+        assert_eq!(replay.divergence(), None);
+        assert_eq!(replay.recorded, replay.replayed);
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        // Change the SYN2 sent in the recording to a DIS, as though the
+        // EndPoint which made it behaved differently.
+        let mut log = record_session();
+        let syn2 = [SOF, 0xc2, 0x66, 0x15, SOF];
+        let idx = log
+            .windows(syn2.len())
+            .position(|window| window == syn2)
+            .unwrap();
+        log[idx..idx + syn2.len()].copy_from_slice(&[SOF, 0xc3, 0xef, 0x04, SOF]);
+
+        let replay = replay(&log).unwrap();
+        let divergence = replay.divergence().unwrap();
+        assert_eq!(divergence.tick, Some(10));
+        assert_eq!(
+            replay.recorded[divergence.index].to_string(),
+            "transmitted DIS"
+        );
+        assert_eq!(
+            replay.replayed[divergence.index].to_string(),
+            "transmitted SYN2"
+        );
+    }
+
+    #[test]
+    fn test_log_errors() {
+        let log = record_session();
+        assert_eq!(parse_log(b"SFPQ").unwrap_err(), LogError::BadMagic);
+        assert_eq!(
+            parse_log(b"SFPR\x09").unwrap_err(),
+            LogError::UnsupportedVersion(9)
+        );
+        assert_eq!(
+            parse_log(&log[..log.len() - 1]).unwrap_err(),
+            LogError::Truncated
+        );
+        assert_eq!(
+            parse_log(b"SFPR\x02\x20\x04\x22\x09").unwrap_err(),
+            LogError::UnknownEvent(9)
+        );
+        assert_eq!(
+            parse_log(b"SFPR\x02\x20\x04\x22\x05\x07").unwrap_err(),
+            LogError::UnknownState(7)
+        );
+
+        // Storage sizes which are too large to allocate are rejected.
+        assert_eq!(
+            replay(b"SFPR\x02\xff\xff\xff\xff\x0f\x04\x22").unwrap_err(),
+            LogError::BadHeader
+        );
+        assert_eq!(
+            replay(b"SFPR\x02\x20\x81\x02\x22").unwrap_err(),
+            LogError::BadHeader
+        );
+        assert_eq!(
+            replay(b"SFPR\x02\x20\x04\x83\x80\x04").unwrap_err(),
+            LogError::BadHeader
+        );
+
+        // A log taken in pieces is the same as one taken all at once.
+        let mut storage = VecStorage::new(8, 2);
+        let mut endpoint = EndPoint::new();
+        let mut recorder = Recorder::new(&endpoint, &mut storage).unwrap();
+        recorder.tick(300);
+        let mut pieces = recorder.take_log();
+        recorder.connect(&mut endpoint, &mut storage);
+        pieces.extend(recorder.take_log());
+        let (_, events) = parse_log(&pieces).unwrap();
+        assert_eq!(events[0], Event::Tick(300));
+        assert_eq!(events[1], Event::Connect);
+        assert_eq!(storage.take_tx_data(), &[SOF, 0xc0, 0x74, 0x36, SOF]);
+    }
+
+    #[test]
+    fn test_not_fresh() {
+        let mut storage = VecStorage::new(8, 2);
+        let mut endpoint = EndPoint::new();
+        endpoint.connect(&mut storage);
+        assert_eq!(Recorder::new(&endpoint, &mut storage).err(), Some(NotFresh));
+
+        // Storage which still holds a packet sent by another EndPoint.
+        let mut storage = VecStorage::new(8, 2);
+        storage.tx_queue().next().store_data(b"Old");
+        assert_eq!(
+            Recorder::new(&EndPoint::new(), &mut storage).err(),
+            Some(NotFresh)
+        );
+        assert!(Recorder::new(&EndPoint::new(), &mut VecStorage::new(8, 2)).is_ok());
+    }
+
+    #[test]
+    fn test_rx_capacity() {
+        // A receive buffer which is smaller than the packets sent is
+        // recorded, so that the replay rejects the same frames.
+        let mut storage = VecStorage::with_rx_capacity(32, 4, 8);
+        let mut endpoint = EndPoint::new();
+        let mut recorder = Recorder::new(&endpoint, &mut storage).unwrap();
+        let mut peer = EndPoint::new();
+        let mut peer_storage = VecStorage::new(32, 4);
+        peer.connect(&mut peer_storage);
+        recorder.parse_bytes(
+            &mut endpoint,
+            &peer_storage.take_tx_data(),
+            &mut storage,
+            |_, _| {},
+        );
+        peer.parse_packet(&storage.take_tx_data(), &mut peer_storage);
+        recorder.parse_bytes(
+            &mut endpoint,
+            &peer_storage.take_tx_data(),
+            &mut storage,
+            |_, _| {},
+        );
+        peer.write_packet(b"Too long for rx_buf", &mut peer_storage);
+        recorder.parse_bytes(
+            &mut endpoint,
+            &peer_storage.take_tx_data(),
+            &mut storage,
+            |_, _| {},
+        );
+
+        let log = recorder.take_log();
+        let replay = replay(&log).unwrap();
+        assert_eq!(replay.header.rx_capacity, 8);
+        assert!(replay
+            .recorded
+            .iter()
+            .any(|event| matches!(event, Event::Rejected(_))));
+        assert_eq!(replay.divergence(), None);
+    }
+
+    struct Collect(Vec<Vec<u8>>);
+
+    impl ChannelHandler for Collect {
+        fn handle_packet(&mut self, data: &[u8]) {
+            self.0.push(data.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_record_mux() {
+        // A Mux owns its EndPoint, so it's given the recording storage and
+        // the inputs are logged separately.
+        let mut storage = VecStorage::new(32, 4);
+        let mut handler = Collect(Vec::new());
+        let mut mux: Mux<U2> = Mux::new();
+        let mut recorder = Recorder::new(mux.endpoint(), &mut storage).unwrap();
+        mux.open(1, &mut handler).unwrap();
+        let mut peer = EndPoint::new();
+        let mut peer_storage = VecStorage::new(32, 4);
+
+        recorder.record_connect();
+        mux.connect(&mut recorder.storage(&mut storage));
+        recorder.record_result(mux.endpoint(), ParseResult::MoreDataNeeded, &mut storage);
+        for _ in 0..2 {
+            peer.parse_packet(&storage.take_tx_data(), &mut peer_storage);
+            let bytes = peer_storage.take_tx_data();
+            recorder.record_received(&bytes);
+            for byte in bytes.iter() {
+                let result = mux.parse_byte(*byte, &mut recorder.storage(&mut storage));
+                recorder.record_result(mux.endpoint(), result, &mut storage);
+            }
+        }
+        assert!(mux.is_connected());
+
+        mux.write_packet(1, b"Hello", &mut recorder.storage(&mut storage))
+            .unwrap();
+        peer.write_packet(b"\x01World", &mut peer_storage);
+        let bytes = peer_storage.take_tx_data();
+        recorder.record_received(&bytes);
+        for byte in bytes.iter() {
+            let result = mux.parse_byte(*byte, &mut recorder.storage(&mut storage));
+            recorder.record_result(mux.endpoint(), result, &mut storage);
+        }
+        assert_eq!(handler.0, vec![b"World".to_vec()]);
+
+        // The frames were still passed on to the storage.
+        let mut delivered = Vec::new();
+        for byte in storage.take_tx_data() {
+            if peer.parse_byte(byte, &mut peer_storage) == ParseResult::UserPacket {
+                delivered.push(peer_storage.rx_data().to_vec());
+            }
+        }
+        assert_eq!(delivered, vec![b"\x01Hello".to_vec()]);
+
+        let log = recorder.take_log();
+        let replay = replay(&log).unwrap();
+        assert!(replay
+            .recorded
+            .contains(&Event::Write(b"\x01Hello".to_vec())));
+        assert_eq!(replay.divergence(), None);
+    }
+}
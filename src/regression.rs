@@ -0,0 +1,254 @@
+// Regression vectors for the wire protocol.
+//
+// Each vector is a script of byte exchanges between an EndPoint and a
+// remote peer. The frames are spelled out as hex bytes (SOF, header,
+// escaped payload, CRC-16 LSB first, SOF), so that a change to our encoder,
+// parser or state machine can't silently change the wire format.
+//
+// The expected bytes were recorded from this crate, not captured from
+// libsfp, so they pin down our own behaviour rather than demonstrate
+// interoperability. Conformance vectors captured from a real libsfp peer
+// (handshake, escaping, NAK/RTX recovery, reconnect) are still missing.
+//
+// Every step performs one action, and then checks the bytes which the
+// EndPoint emitted, the user packets which it delivered, and whether it
+// ended up connected.
+
+use std::vec::Vec;
+
+use crate::testutils::setup_log;
+use crate::vecstorage::VecStorage;
+use crate::{EndPoint, ParseResult};
+use Action::*;
+
+enum Action {
+    // Call connect() on the EndPoint.
+    Connect,
+    // Send a user packet from the EndPoint.
+    Write(&'static [u8]),
+    // Feed bytes sent by the peer into the EndPoint.
+    Peer(&'static str),
+}
+
+struct Step {
+    action: Action,
+    emit: &'static str,
+    deliver: &'static [&'static [u8]],
+    connected: bool,
+}
+
+const fn step(
+    action: Action,
+    emit: &'static str,
+    deliver: &'static [&'static [u8]],
+    connected: bool,
+) -> Step {
+    Step {
+        action,
+        emit,
+        deliver,
+        connected,
+    }
+}
+
+// Control frames.
+const SYN0: &str = "7e c0 74 36 7e";
+const SYN1: &str = "7e c1 fd 27 7e";
+const SYN2: &str = "7e c2 66 15 7e";
+const DIS: &str = "7e c3 ef 04 7e";
+const NAK0: &str = "7e 80 70 74 7e";
+
+// We initiate the connection.
+const HANDSHAKE_INITIATOR: &[Step] = &[
+    step(Connect, SYN0, &[], false),
+    step(Peer(SYN1), SYN2, &[], true),
+];
+
+// The peer initiates the connection.
+const HANDSHAKE_RESPONDER: &[Step] = &[
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN2), "", &[], true),
+];
+
+// Both sides send SYN0 at the same time.
+const HANDSHAKE_SIMULTANEOUS: &[Step] = &[
+    step(Connect, SYN0, &[], false),
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN1), SYN2, &[], true),
+    step(Peer(SYN2), "", &[], true),
+];
+
+// Frames received before the handshake completes are answered with DIS or
+// a repeated SYN0, and never delivered.
+const NOT_CONNECTED: &[Step] = &[
+    step(Peer("7e 00 48 65 6c 6c 6f d4 6c 7e"), DIS, &[], false),
+    step(Peer(SYN1), DIS, &[], false),
+    step(Peer(SYN2), DIS, &[], false),
+    step(Peer(NAK0), "", &[], false),
+    step(Write(b"Hello"), "", &[], false),
+    step(Connect, SYN0, &[], false),
+    step(Peer("7e 00 48 65 6c 6c 6f d4 6c 7e"), SYN0, &[], false),
+    step(Peer(SYN2), SYN0, &[], false),
+    step(Peer(SYN1), SYN2, &[], true),
+];
+
+// User frames with SOF and ESC in the payload and in the CRC, including
+// back to back frames in a single chunk.
+const USER_FRAMES_ESCAPING: &[Step] = &[
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN2), "", &[], true),
+    step(
+        Peer("7e 00 7d 5e 7d 5d 01 28 3d 7e 7e 01 7d 5d 5d 7d 5e 5e 4a b8 7e"),
+        "",
+        &[b"\x7e\x7d\x01", b"\x7d\x5d\x7e\x5e"],
+        true,
+    ),
+    step(Peer("7e 02 50 69 6e 67 46 7d 5d 7e"), "", &[b"Ping"], true),
+    step(
+        Write(b"\x7e\x7d\x01"),
+        "7e 00 7d 5e 7d 5d 01 28 3d 7e",
+        &[],
+        true,
+    ),
+    step(
+        Write(b"\x7d\x5d\x7e\x5e"),
+        "7e 01 7d 5d 5d 7d 5e 5e 4a b8 7e",
+        &[],
+        true,
+    ),
+    step(Write(b"Ping"), "7e 02 50 69 6e 67 46 7d 5d 7e", &[], true),
+];
+
+// Lost and corrupted frames are recovered using NAK and RTX.
+const NAK_RTX_RECOVERY: &[Step] = &[
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN2), "", &[], true),
+    step(Write(b"One"), "7e 00 4f 6e 65 81 7b 7e", &[], true),
+    step(Write(b"Two"), "7e 01 54 77 6f da 26 7e", &[], true),
+    step(Write(b"Three"), "7e 02 54 68 72 65 65 b1 c1 7e", &[], true),
+    // The peer lost "Two" and asks for everything from seq 1.
+    step(
+        Peer("7e 81 f9 65 7e"),
+        "7e 41 54 77 6f 6d 30 7e 7e 42 54 68 72 65 65 60 c3 7e",
+        &[],
+        true,
+    ),
+    // We lost the peer's seq 0, so its seq 1 is out of order.
+    step(Peer("7e 01 50 6f 6e 67 53 b6 7e"), NAK0, &[], true),
+    step(
+        Peer("7e 40 50 69 6e 67 ec aa 7e 7e 41 50 6f 6e 67 71 77 7e"),
+        "",
+        &[b"Ping", b"Pong"],
+        true,
+    ),
+    // Duplicate retransmissions are ignored without a NAK.
+    step(Peer("7e 41 50 6f 6e 67 71 77 7e"), "", &[], true),
+    // A frame with a bad CRC is dropped, and the good copy accepted.
+    step(Peer("7e 02 48 65 6c 6c 6e 82 64 7e"), "", &[], true),
+    step(Peer("7e 02 48 65 6c 6c 6f 82 64 7e"), "", &[b"Hello"], true),
+];
+
+// A SYN1 or SYN2 from the peer while connected causes the history to be
+// replayed from seq 0, while a SYN0 starts a new session and discards it.
+const RECONNECT_HISTORY_REPLAY: &[Step] = &[
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN2), "", &[], true),
+    step(Write(b"One"), "7e 00 4f 6e 65 81 7b 7e", &[], true),
+    step(Write(b"Two"), "7e 01 54 77 6f da 26 7e", &[], true),
+    step(
+        Peer(SYN1),
+        "7e c2 66 15 7e 7e 40 4f 6e 65 36 6d 7e 7e 41 54 77 6f 6d 30 7e",
+        &[],
+        true,
+    ),
+    step(
+        Peer(SYN2),
+        "7e 40 4f 6e 65 36 6d 7e 7e 41 54 77 6f 6d 30 7e",
+        &[],
+        true,
+    ),
+    step(Write(b"Three"), "7e 02 54 68 72 65 65 b1 c1 7e", &[], true),
+    step(Peer(SYN0), SYN1, &[], false),
+    step(Peer(SYN2), "", &[], true),
+    step(Peer(NAK0), "", &[], true),
+    step(Write(b"Hello"), "7e 00 48 65 6c 6c 6f d4 6c 7e", &[], true),
+    step(Peer(DIS), "", &[], false),
+    step(Write(b"Hello"), "", &[], false),
+    step(Peer("7e 01 48 65 6c 6c 6f ff 68 7e"), DIS, &[], false),
+];
+
+fn hex(s: &str) -> Vec<u8> {
+    s.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+        .collect()
+}
+
+fn run(name: &str, steps: &[Step]) {
+    setup_log();
+
+    let mut storage = VecStorage::new(64, 8);
+    let mut ep = EndPoint::new();
+    for (idx, step) in steps.iter().enumerate() {
+        let mut delivered = Vec::new();
+        match step.action {
+            Connect => ep.connect(&mut storage),
+            Write(data) => ep.write_packet(data, &mut storage),
+            Peer(bytes) => {
+                for byte in hex(bytes) {
+                    if ep.parse_byte(byte, &mut storage) == ParseResult::UserPacket {
+                        delivered.push(storage.rx_data().to_vec());
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            storage.take_tx_data(),
+            hex(step.emit),
+            "{} step {}: emitted bytes",
+            name,
+            idx
+        );
+        assert_eq!(delivered, step.deliver, "{} step {}: delivered", name, idx);
+        assert_eq!(
+            ep.is_connected(),
+            step.connected,
+            "{} step {}: connected",
+            name,
+            idx
+        );
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        run("initiator", HANDSHAKE_INITIATOR);
+        run("responder", HANDSHAKE_RESPONDER);
+        run("simultaneous", HANDSHAKE_SIMULTANEOUS);
+        run("not_connected", NOT_CONNECTED);
+    }
+
+    #[test]
+    fn test_user_frames_escaping() {
+        run("user_frames_escaping", USER_FRAMES_ESCAPING);
+    }
+
+    #[test]
+    fn test_nak_rtx_recovery() {
+        run("nak_rtx_recovery", NAK_RTX_RECOVERY);
+    }
+
+    #[test]
+    fn test_reconnect_history_replay() {
+        run("reconnect_history_replay", RECONNECT_HISTORY_REPLAY);
+    }
+}
@@ -0,0 +1,445 @@
+use generic_array::{ArrayLength, GenericArray};
+use log::warn;
+
+use crate::traits::Storage;
+use crate::EndPoint;
+
+/// Identifies a request so that its response can be matched up with it.
+pub type CorrelationId = u8;
+
+/// Ticks are supplied by the caller and can use whatever time base the
+/// application already uses to drive its link (e.g. a millisecond counter).
+/// Tick counts are allowed to wrap.
+pub type Ticks = u32;
+
+/// Application defined error code sent back to the caller when a request fails.
+pub type ErrorCode = u8;
+
+// Each RPC message starts with a kind byte followed by the correlation id.
+// Error messages then have an error code byte. Everything after that is the
+// payload.
+const RPC_REQUEST: u8 = 0;
+const RPC_RESPONSE: u8 = 1;
+const RPC_ERROR: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum RpcError {
+    /// The EndPoint isn't connected.
+    NotConnected,
+    /// All of the request slots are in use.
+    TooManyRequests,
+    /// Every correlation id is in use by a pending request. This can only
+    /// happen when N is larger than the number of ids.
+    NoFreeId,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RpcEvent<'a> {
+    /// A request was received from the remote side. It should be answered
+    /// by calling `respond` or `respond_error` with the same id.
+    Request { id: CorrelationId, data: &'a [u8] },
+    /// A response was received for an outstanding request.
+    Response { id: CorrelationId, data: &'a [u8] },
+    /// The remote side reported an error for an outstanding request.
+    RemoteError {
+        id: CorrelationId,
+        code: ErrorCode,
+        data: &'a [u8],
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PendingRequest {
+    id: CorrelationId,
+    start: Ticks,
+    timeout: Ticks,
+}
+
+impl PendingRequest {
+    fn is_expired(&self, now: Ticks) -> bool {
+        now.wrapping_sub(self.start) >= self.timeout
+    }
+}
+
+/// Implements request/response on top of an EndPoint. Each side of the link
+/// can issue requests and answer requests from the other side.
+///
+/// N determines the maximum number of requests which can be outstanding at
+/// the same time.
+pub struct Rpc<N: ArrayLength> {
+    next_id: CorrelationId,
+    pending: GenericArray<Option<PendingRequest>, N>,
+}
+
+impl<N: ArrayLength> Default for Rpc<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: ArrayLength> Rpc<N> {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            pending: Default::default(),
+        }
+    }
+
+    /// Returns the number of requests which are waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.pending.iter().filter(|p| p.is_some()).count()
+    }
+
+    /// Determines if the indicated request is waiting for a response.
+    pub fn is_pending(&self, id: CorrelationId) -> bool {
+        self.pending.iter().flatten().any(|p| p.id == id)
+    }
+
+    /// Forgets about all outstanding requests. Typically called when the
+    /// link is reconnected.
+    pub fn reset(&mut self) {
+        for slot in self.pending.iter_mut() {
+            *slot = None;
+        }
+    }
+
+    fn alloc_id(&mut self) -> Option<CorrelationId> {
+        for _ in 0..=CorrelationId::MAX as usize {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.is_pending(id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Sends a request to the remote side. If no response arrives within
+    /// `timeout` ticks of `now` then the request will be reported by `poll`.
+    pub fn call(
+        &mut self,
+        data: &[u8],
+        now: Ticks,
+        timeout: Ticks,
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Result<CorrelationId, RpcError> {
+        if !endpoint.is_connected() {
+            return Err(RpcError::NotConnected);
+        }
+        let slot_idx = self
+            .pending
+            .iter()
+            .position(|p| p.is_none())
+            .ok_or(RpcError::TooManyRequests)?;
+        let id = self.alloc_id().ok_or(RpcError::NoFreeId)?;
+        self.pending[slot_idx] = Some(PendingRequest {
+            id,
+            start: now,
+            timeout,
+        });
+        endpoint.write_packet_vectored(&[&[RPC_REQUEST, id], data], storage);
+        Ok(id)
+    }
+
+    /// Sends a successful response to a request received from the remote side.
+    pub fn respond(
+        &mut self,
+        id: CorrelationId,
+        data: &[u8],
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Result<(), RpcError> {
+        if !endpoint.is_connected() {
+            return Err(RpcError::NotConnected);
+        }
+        endpoint.write_packet_vectored(&[&[RPC_RESPONSE, id], data], storage);
+        Ok(())
+    }
+
+    /// Reports an error for a request received from the remote side.
+    pub fn respond_error(
+        &mut self,
+        id: CorrelationId,
+        code: ErrorCode,
+        data: &[u8],
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Result<(), RpcError> {
+        if !endpoint.is_connected() {
+            return Err(RpcError::NotConnected);
+        }
+        endpoint.write_packet_vectored(&[&[RPC_ERROR, id, code], data], storage);
+        Ok(())
+    }
+
+    /// Processes a user packet received by the EndPoint. Responses which
+    /// don't match an outstanding request (e.g. because the request already
+    /// timed out) and malformed packets are dropped, in which case None is
+    /// returned.
+    pub fn handle_packet<'a>(&mut self, data: &'a [u8]) -> Option<RpcEvent<'a>> {
+        if data.len() < 2 {
+            warn!("Dropping RPC packet which is too short");
+            return None;
+        }
+        let id = data[1];
+        let event = match data[0] {
+            RPC_REQUEST => {
+                return Some(RpcEvent::Request {
+                    id,
+                    data: &data[2..],
+                });
+            }
+            RPC_RESPONSE => RpcEvent::Response {
+                id,
+                data: &data[2..],
+            },
+            RPC_ERROR if data.len() >= 3 => RpcEvent::RemoteError {
+                id,
+                code: data[2],
+                data: &data[3..],
+            },
+            RPC_ERROR => {
+                warn!("Dropping RPC error without an error code");
+                return None;
+            }
+            kind => {
+                warn!("Dropping RPC packet with unknown kind {}", kind);
+                return None;
+            }
+        };
+
+        // Only forget about the request once a valid reply has arrived.
+        let slot = self.pending.iter_mut().find(|p| match p {
+            Some(pending) => pending.id == id,
+            None => false,
+        });
+        match slot {
+            Some(slot) => {
+                *slot = None;
+                Some(event)
+            }
+            None => {
+                warn!("Dropping RPC response for unknown request {}", id);
+                None
+            }
+        }
+    }
+
+    /// Checks for requests which have timed out. The id of one expired
+    /// request is returned each time this is called, so it should be called
+    /// until it returns None.
+    pub fn poll(&mut self, now: Ticks) -> Option<CorrelationId> {
+        for slot in self.pending.iter_mut() {
+            if let Some(pending) = slot {
+                if pending.is_expired(now) {
+                    let id = pending.id;
+                    *slot = None;
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{connect_endpoints, setup_log, TestStorage};
+    use crate::ParseResult;
+    use typenum::{U2, U300};
+
+    struct Side {
+        ep: EndPoint,
+        storage: TestStorage,
+        rpc: Rpc<U2>,
+    }
+
+    fn connected_pair() -> (Side, Side) {
+        let mut side1 = Side {
+            ep: EndPoint::new(),
+            storage: TestStorage::new(),
+            rpc: Rpc::new(),
+        };
+        let mut side2 = Side {
+            ep: EndPoint::new(),
+            storage: TestStorage::new(),
+            rpc: Rpc::new(),
+        };
+        connect_endpoints(
+            &mut side1.ep,
+            &mut side1.storage,
+            &mut side2.ep,
+            &mut side2.storage,
+        );
+        (side1, side2)
+    }
+
+    // Delivers the packet most recently written by `from` to `to`.
+    fn deliver(from: &Side, to: &mut Side) {
+        assert_eq!(
+            to.ep.parse_packet(from.storage.tx_data(), &mut to.storage),
+            ParseResult::UserPacket
+        );
+    }
+
+    #[test]
+    fn test_call_response() {
+        setup_log();
+        let (mut side1, mut side2) = connected_pair();
+
+        let id = side1
+            .rpc
+            .call(b"ping", 0, 10, &mut side1.ep, &mut side1.storage)
+            .unwrap();
+        assert!(side1.rpc.is_pending(id));
+
+        deliver(&side1, &mut side2);
+        let event = side2.rpc.handle_packet(side2.storage.rx_data());
+        assert_eq!(event, Some(RpcEvent::Request { id, data: b"ping" }));
+        side2
+            .rpc
+            .respond(id, b"pong", &mut side2.ep, &mut side2.storage)
+            .unwrap();
+
+        deliver(&side2, &mut side1);
+        let event = side1.rpc.handle_packet(side1.storage.rx_data());
+        assert_eq!(event, Some(RpcEvent::Response { id, data: b"pong" }));
+        assert_eq!(side1.rpc.pending(), 0);
+
+        // A second response for the same request is dropped.
+        side2
+            .rpc
+            .respond(id, b"pong", &mut side2.ep, &mut side2.storage)
+            .unwrap();
+        deliver(&side2, &mut side1);
+        assert_eq!(side1.rpc.handle_packet(side1.storage.rx_data()), None);
+    }
+
+    #[test]
+    fn test_remote_error() {
+        setup_log();
+        let (mut side1, mut side2) = connected_pair();
+
+        let id = side1
+            .rpc
+            .call(b"bad", 0, 10, &mut side1.ep, &mut side1.storage)
+            .unwrap();
+        deliver(&side1, &mut side2);
+        side2
+            .rpc
+            .respond_error(id, 42, b"oops", &mut side2.ep, &mut side2.storage)
+            .unwrap();
+
+        deliver(&side2, &mut side1);
+        let event = side1.rpc.handle_packet(side1.storage.rx_data());
+        assert_eq!(
+            event,
+            Some(RpcEvent::RemoteError {
+                id,
+                code: 42,
+                data: b"oops"
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_error() {
+        setup_log();
+        let (mut side1, _side2) = connected_pair();
+
+        let id = side1
+            .rpc
+            .call(b"bad", 0, 10, &mut side1.ep, &mut side1.storage)
+            .unwrap();
+
+        // An error without a code is dropped, and the request is still
+        // waiting for a proper reply.
+        assert_eq!(side1.rpc.handle_packet(&[RPC_ERROR, id]), None);
+        assert!(side1.rpc.is_pending(id));
+        assert_eq!(
+            side1.rpc.handle_packet(&[RPC_ERROR, id, 42]),
+            Some(RpcEvent::RemoteError {
+                id,
+                code: 42,
+                data: &[]
+            })
+        );
+        assert!(!side1.rpc.is_pending(id));
+    }
+
+    #[test]
+    fn test_timeout() {
+        setup_log();
+        let (mut side1, mut side2) = connected_pair();
+
+        // Make sure that tick wraparound is handled.
+        let start = Ticks::MAX - 5;
+        let id1 = side1
+            .rpc
+            .call(b"1", start, 10, &mut side1.ep, &mut side1.storage)
+            .unwrap();
+        let id2 = side1
+            .rpc
+            .call(b"2", start, 20, &mut side1.ep, &mut side1.storage)
+            .unwrap();
+        assert_ne!(id1, id2);
+        assert_eq!(
+            side1
+                .rpc
+                .call(b"3", start, 20, &mut side1.ep, &mut side1.storage),
+            Err(RpcError::TooManyRequests)
+        );
+
+        assert_eq!(side1.rpc.poll(start.wrapping_add(9)), None);
+        assert_eq!(side1.rpc.poll(start.wrapping_add(10)), Some(id1));
+        assert_eq!(side1.rpc.poll(start.wrapping_add(10)), None);
+        assert_eq!(side1.rpc.poll(start.wrapping_add(25)), Some(id2));
+        assert_eq!(side1.rpc.pending(), 0);
+
+        // A response which arrives after the timeout is dropped.
+        side2
+            .rpc
+            .respond(id1, b"late", &mut side2.ep, &mut side2.storage)
+            .unwrap();
+        deliver(&side2, &mut side1);
+        assert_eq!(side1.rpc.handle_packet(side1.storage.rx_data()), None);
+    }
+
+    #[test]
+    fn test_no_free_id() {
+        setup_log();
+        let (mut side1, _side2) = connected_pair();
+        let mut rpc: Rpc<U300> = Rpc::new();
+        for _ in 0..256 {
+            rpc.call(b"", 0, 10, &mut side1.ep, &mut side1.storage)
+                .unwrap();
+        }
+        assert_eq!(rpc.pending(), 256);
+        assert_eq!(
+            rpc.call(b"", 0, 10, &mut side1.ep, &mut side1.storage),
+            Err(RpcError::NoFreeId)
+        );
+        assert_eq!(rpc.pending(), 256);
+    }
+
+    #[test]
+    fn test_not_connected() {
+        setup_log();
+        let mut ep = EndPoint::new();
+        let mut storage = TestStorage::new();
+        let mut rpc: Rpc<U2> = Rpc::new();
+        assert_eq!(
+            rpc.call(b"1", 0, 10, &mut ep, &mut storage),
+            Err(RpcError::NotConnected)
+        );
+        assert_eq!(rpc.pending(), 0);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::traits::{PacketBuffer, Storage};
+use crate::EndPoint;
+
+/// Priority level of a queued packet. 0 is the highest priority.
+pub type Priority = usize;
+
+#[derive(Debug, PartialEq)]
+pub enum SchedulerError {
+    /// There is no queue for the requested priority level.
+    InvalidPriority,
+    /// The queue for the requested priority level is full.
+    QueueFull,
+    /// The packet is larger than the buffers in the queue.
+    PacketTooLarge,
+}
+
+/// The FrameQueue is a FIFO of packets waiting to be sent. Packets are
+/// stored in a circular fashion. `head` points to the oldest packet.
+pub trait FrameQueue {
+    /// Returns the maximum number of packets which can be stored.
+    fn capacity(&self) -> usize;
+
+    /// Returns the number of packets currently in the queue.
+    fn len(&self) -> usize;
+
+    /// Sets the number of packets currently in the queue.
+    fn set_len(&mut self, len: usize);
+
+    /// Returns the index of the oldest packet in the queue.
+    fn head(&self) -> usize;
+
+    /// Sets the index of the oldest packet in the queue.
+    fn set_head(&mut self, idx: usize);
+
+    /// Returns the idx'th packet from the queue.
+    fn packet(&mut self, idx: usize) -> Option<&mut dyn PacketBuffer>;
+
+    /// Determines if the queue is currently empty or not.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Determines if the queue is currently full or not.
+    fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    /// Removes all packets from the queue.
+    fn clear(&mut self) {
+        self.set_len(0);
+        self.set_head(0);
+    }
+
+    /// Adds a copy of `data` to the end of the queue.
+    fn push(&mut self, data: &[u8]) -> Result<(), SchedulerError> {
+        if self.is_full() {
+            return Err(SchedulerError::QueueFull);
+        }
+        let idx = (self.head() + self.len()) % self.capacity();
+        let packet = self.packet(idx).ok_or(SchedulerError::QueueFull)?;
+        if data.len() > packet.capacity() {
+            return Err(SchedulerError::PacketTooLarge);
+        }
+        packet.store_data(data);
+        self.set_len(self.len() + 1);
+        Ok(())
+    }
+
+    /// Returns a reference to the oldest packet in the queue.
+    fn front(&mut self) -> Option<&mut dyn PacketBuffer> {
+        if self.is_empty() {
+            return None;
+        }
+        self.packet(self.head())
+    }
+
+    /// Removes the oldest packet from the queue.
+    fn pop(&mut self) {
+        if !self.is_empty() {
+            self.set_head((self.head() + 1) % self.capacity());
+            self.set_len(self.len() - 1);
+        }
+    }
+}
+
+pub trait SchedulerStorage {
+    /// Returns the number of priority levels.
+    fn num_priorities(&self) -> usize;
+
+    /// Returns the FrameQueue used for the indicated priority level.
+    fn queue(&mut self, priority: Priority) -> Option<&mut dyn FrameQueue>;
+}
+
+/// The Scheduler holds outbound user packets until the link is ready for
+/// them, and then sends them highest priority first. Packets with the same
+/// priority are sent in the order they were queued.
+///
+/// Sequence numbers are only assigned by the EndPoint as each packet is
+/// actually written, so packets are always sequenced in the order that they
+/// appear on the wire.
+pub struct Scheduler<'a> {
+    storage: &'a mut dyn SchedulerStorage,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(storage: &'a mut dyn SchedulerStorage) -> Self {
+        Self { storage }
+    }
+
+    /// Queues a user packet to be sent with the indicated priority.
+    pub fn queue_packet(&mut self, priority: Priority, data: &[u8]) -> Result<(), SchedulerError> {
+        self.storage
+            .queue(priority)
+            .ok_or(SchedulerError::InvalidPriority)?
+            .push(data)
+    }
+
+    /// Returns the total number of packets waiting to be sent.
+    pub fn pending(&mut self) -> usize {
+        let mut pending = 0;
+        for priority in 0..self.storage.num_priorities() {
+            if let Some(queue) = self.storage.queue(priority) {
+                pending += queue.len();
+            }
+        }
+        pending
+    }
+
+    /// Removes all queued packets.
+    pub fn clear(&mut self) {
+        for priority in 0..self.storage.num_priorities() {
+            if let Some(queue) = self.storage.queue(priority) {
+                queue.clear();
+            }
+        }
+    }
+
+    /// Writes the highest priority queued packet to the EndPoint and returns
+    /// its priority. Nothing is written if no packets are queued or if the
+    /// EndPoint isn't connected, in which case None is returned.
+    pub fn send_next(
+        &mut self,
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Option<Priority> {
+        if !endpoint.is_connected() {
+            return None;
+        }
+        for priority in 0..self.storage.num_priorities() {
+            if let Some(queue) = self.storage.queue(priority) {
+                if let Some(packet) = queue.front() {
+                    endpoint.write_packet(packet.data(), storage);
+                    queue.pop();
+                    return Some(priority);
+                }
+            }
+        }
+        None
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{connect_endpoints, setup_log, TestPacketBuffer, TestStorage};
+    use crate::ParseResult;
+    use std::vec::Vec;
+
+    const QUEUE_SIZE: usize = 4;
+
+    #[derive(Default)]
+    struct TestFrameQueue {
+        len: usize,
+        head: usize,
+        packet: [TestPacketBuffer; QUEUE_SIZE],
+    }
+
+    impl FrameQueue for TestFrameQueue {
+        fn capacity(&self) -> usize {
+            QUEUE_SIZE
+        }
+
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn set_len(&mut self, len: usize) {
+            self.len = len;
+        }
+
+        fn head(&self) -> usize {
+            self.head
+        }
+
+        fn set_head(&mut self, idx: usize) {
+            self.head = idx;
+        }
+
+        fn packet(&mut self, idx: usize) -> Option<&mut dyn PacketBuffer> {
+            match self.packet.get_mut(idx) {
+                Some(packet) => Some(packet),
+                None => None,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct TestSchedulerStorage {
+        queue: [TestFrameQueue; 2],
+    }
+
+    impl SchedulerStorage for TestSchedulerStorage {
+        fn num_priorities(&self) -> usize {
+            self.queue.len()
+        }
+
+        fn queue(&mut self, priority: Priority) -> Option<&mut dyn FrameQueue> {
+            match self.queue.get_mut(priority) {
+                Some(queue) => Some(queue),
+                None => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_queue() {
+        setup_log();
+        let mut queue = TestFrameQueue::default();
+
+        assert!(queue.front().is_none());
+        for i in 0..QUEUE_SIZE {
+            assert_eq!(queue.push(&[i as u8]), Ok(()));
+        }
+        assert_eq!(queue.push(&[0xff]), Err(SchedulerError::QueueFull));
+
+        // Wrap the queue around a few times and make sure that things come
+        // back out in FIFO order.
+        for i in 0..(3 * QUEUE_SIZE) {
+            assert_eq!(queue.front().unwrap().data(), &[i as u8]);
+            queue.pop();
+            assert_eq!(queue.push(&[(i + QUEUE_SIZE) as u8]), Ok(()));
+        }
+        assert_eq!(queue.len(), QUEUE_SIZE);
+
+        let big = [0u8; 257];
+        queue.clear();
+        assert_eq!(queue.push(&big), Err(SchedulerError::PacketTooLarge));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_priority() {
+        setup_log();
+        let mut storage1 = TestStorage::new();
+        let mut storage2 = TestStorage::new();
+        let mut ep1 = EndPoint::new();
+        let mut ep2 = EndPoint::new();
+        let mut sched_storage = TestSchedulerStorage::default();
+        let mut sched = Scheduler::new(&mut sched_storage);
+
+        sched.queue_packet(1, b"Bulk 1").unwrap();
+        sched.queue_packet(1, b"Bulk 2").unwrap();
+        assert_eq!(
+            sched.queue_packet(2, b"Bad"),
+            Err(SchedulerError::InvalidPriority)
+        );
+
+        // Nothing gets sent until we're connected.
+        assert_eq!(sched.send_next(&mut ep1, &mut storage1), None);
+        assert_eq!(sched.pending(), 2);
+        connect_endpoints(&mut ep1, &mut storage1, &mut ep2, &mut storage2);
+
+        let mut received: Vec<Vec<u8>> = Vec::new();
+        let mut send_next = |sched: &mut Scheduler, expected: Option<Priority>| {
+            assert_eq!(sched.send_next(&mut ep1, &mut storage1), expected);
+            if expected.is_some() {
+                assert_eq!(
+                    ep2.parse_packet(storage1.tx_data(), &mut storage2),
+                    ParseResult::UserPacket
+                );
+                received.push(storage2.rx_data().to_vec());
+            }
+        };
+
+        send_next(&mut sched, Some(1));
+        sched.queue_packet(0, b"Urgent").unwrap();
+        send_next(&mut sched, Some(0));
+        send_next(&mut sched, Some(1));
+        send_next(&mut sched, None);
+        assert_eq!(sched.pending(), 0);
+
+        assert_eq!(
+            received,
+            vec![b"Bulk 1".to_vec(), b"Urgent".to_vec(), b"Bulk 2".to_vec()]
+        );
+    }
+}
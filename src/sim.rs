@@ -0,0 +1,486 @@
+use core::mem;
+use log::debug;
+use std::vec::Vec;
+
+use crate::traits::SOF;
+use crate::vecstorage::VecStorage;
+use crate::{EndPoint, ParseResult};
+
+/// Time on the simulator's virtual clock.
+pub type Ticks = u64;
+
+/// Describes how the channel in each direction misbehaves. Probabilities
+/// are in the range 0.0 to 1.0.
+#[derive(Clone, Debug)]
+pub struct ChannelConfig {
+    /// Probability that each byte has a random bit flipped.
+    pub corrupt: f64,
+
+    /// Probability that a frame is dropped.
+    pub drop: f64,
+
+    /// Probability that a frame is delivered twice.
+    pub duplicate: f64,
+
+    /// Probability that a frame is held back by `reorder_delay`, allowing
+    /// later frames to overtake it.
+    pub reorder: f64,
+
+    /// Extra delay applied to reordered frames.
+    pub reorder_delay: Ticks,
+
+    /// Minimum time taken for a frame to cross the channel.
+    pub latency: Ticks,
+
+    /// Maximum random time added to `latency`.
+    pub jitter: Ticks,
+}
+
+impl Default for ChannelConfig {
+    /// A perfect channel, which delivers every frame on the next tick.
+    fn default() -> Self {
+        Self {
+            corrupt: 0.0,
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: 5,
+            latency: 1,
+            jitter: 0,
+        }
+    }
+}
+
+/// Counts of what happened to the frames sent over a channel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelStats {
+    pub frames: usize,
+    pub corrupted: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    fn idx(self) -> usize {
+        match self {
+            Side::A => 0,
+            Side::B => 1,
+        }
+    }
+}
+
+// A small seeded PRNG (xorshift64*), so that runs are reproducible without
+// pulling in a dependency.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Scramble the seed (splitmix64) so that small seeds, including
+        // zero, give well mixed states.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self {
+            state: (z ^ (z >> 31)) | 1,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: Ticks,
+    id: u64,
+    bytes: Vec<u8>,
+}
+
+struct Channel {
+    config: ChannelConfig,
+    stats: ChannelStats,
+    in_flight: Vec<InFlight>,
+    next_id: u64,
+}
+
+impl Channel {
+    fn new(config: ChannelConfig) -> Self {
+        Self {
+            config,
+            stats: ChannelStats::default(),
+            in_flight: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    fn send(&mut self, mut frame: Vec<u8>, now: Ticks, rng: &mut Rng) {
+        let config = &self.config;
+        self.stats.frames += 1;
+        if rng.chance(config.drop) {
+            self.stats.dropped += 1;
+            return;
+        }
+        let mut corrupted = false;
+        for byte in frame.iter_mut() {
+            if rng.chance(config.corrupt) {
+                *byte ^= 1 << rng.below(8);
+                corrupted = true;
+            }
+        }
+        if corrupted {
+            self.stats.corrupted += 1;
+        }
+        let mut deliver_at = now + config.latency + rng.below(config.jitter + 1);
+        if rng.chance(config.reorder) {
+            self.stats.reordered += 1;
+            deliver_at += config.reorder_delay;
+        }
+        if rng.chance(config.duplicate) {
+            self.stats.duplicated += 1;
+            self.push(deliver_at + 1, frame.clone());
+        }
+        self.push(deliver_at, frame);
+    }
+
+    fn push(&mut self, deliver_at: Ticks, bytes: Vec<u8>) {
+        self.in_flight.push(InFlight {
+            deliver_at,
+            id: self.next_id,
+            bytes,
+        });
+        self.next_id += 1;
+    }
+
+    // Removes the frames which are due, in delivery order.
+    fn take_due(&mut self, now: Ticks) -> Vec<Vec<u8>> {
+        let (mut due, pending): (Vec<_>, Vec<_>) = mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|frame| frame.deliver_at <= now);
+        self.in_flight = pending;
+        due.sort_by_key(|frame| (frame.deliver_at, frame.id));
+        due.into_iter().map(|frame| frame.bytes).collect()
+    }
+}
+
+/// Splits the bytes written by an EndPoint into individual frames, each of
+/// which starts and ends with a SOF.
+pub fn split_frames(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut frame = Vec::new();
+    for byte in bytes.iter() {
+        frame.push(*byte);
+        if *byte == SOF && frame.len() > 1 {
+            frames.push(mem::take(&mut frame));
+        }
+    }
+    if !frame.is_empty() {
+        frames.push(frame);
+    }
+    frames
+}
+
+struct Peer {
+    endpoint: EndPoint,
+    storage: VecStorage,
+    received: Vec<Vec<u8>>,
+    connecting: bool,
+    next_connect: Ticks,
+}
+
+/// Connects two EndPoints through a pair of simulated channels, driven by a
+/// virtual clock. All of the misbehaviour is derived from the seed, so a
+/// failing run can be reproduced exactly.
+///
+/// Since SFP has no timers of its own, a side which has been asked to
+/// connect restarts the handshake every `connect_interval` ticks until it's
+/// connected, which is what SfpLink and SfpStream do.
+pub struct Simulator {
+    now: Ticks,
+    rng: Rng,
+    peers: [Peer; 2],
+    // channels[0] carries A -> B and channels[1] carries B -> A.
+    channels: [Channel; 2],
+    connect_interval: Ticks,
+}
+
+impl Simulator {
+    /// Creates a simulator whose EndPoints support packets of up to
+    /// `packet_size` bytes and keep `history_len` packets for
+    /// retransmission.
+    pub fn new(seed: u64, config: ChannelConfig, packet_size: usize, history_len: usize) -> Self {
+        let peer = || Peer {
+            endpoint: EndPoint::new(),
+            storage: VecStorage::new(packet_size, history_len),
+            received: Vec::new(),
+            connecting: false,
+            next_connect: 0,
+        };
+        Self {
+            now: 0,
+            rng: Rng::new(seed),
+            peers: [peer(), peer()],
+            channels: [Channel::new(config.clone()), Channel::new(config)],
+            connect_interval: 50,
+        }
+    }
+
+    pub fn now(&self) -> Ticks {
+        self.now
+    }
+
+    /// Sets how long to wait for the handshake before restarting it.
+    pub fn set_connect_interval(&mut self, interval: Ticks) {
+        self.connect_interval = interval;
+    }
+
+    /// Changes the behaviour of both channels. Frames already in flight are
+    /// unaffected.
+    pub fn set_channel_config(&mut self, config: ChannelConfig) {
+        self.channels[0].config = config.clone();
+        self.channels[1].config = config;
+    }
+
+    /// Returns the stats for the channel which carries frames sent by
+    /// `side`.
+    pub fn stats(&self, side: Side) -> &ChannelStats {
+        &self.channels[side.idx()].stats
+    }
+
+    /// Starts the handshake from `side`, which will be retried until it's
+    /// connected.
+    pub fn connect(&mut self, side: Side) {
+        let peer = &mut self.peers[side.idx()];
+        peer.connecting = true;
+        peer.next_connect = self.now;
+    }
+
+    pub fn is_connected(&self, side: Side) -> bool {
+        self.peers[side.idx()].endpoint.is_connected()
+    }
+
+    pub fn endpoint(&self, side: Side) -> &EndPoint {
+        &self.peers[side.idx()].endpoint
+    }
+
+    /// Sends a user packet from `side`.
+    pub fn send(&mut self, side: Side, data: &[u8]) {
+        let peer = &mut self.peers[side.idx()];
+        peer.endpoint.write_packet(data, &mut peer.storage);
+    }
+
+    /// Returns the user packets received by `side`, in the order that they
+    /// were received.
+    pub fn received(&self, side: Side) -> &[Vec<u8>] {
+        &self.peers[side.idx()].received
+    }
+
+    /// Returns true if there's nothing waiting to be sent or in flight.
+    pub fn is_idle(&self) -> bool {
+        self.peers
+            .iter()
+            .all(|peer| peer.storage.tx_data().is_empty())
+            && self.channels.iter().all(|chan| chan.in_flight.is_empty())
+    }
+
+    /// Advances the virtual clock by one tick.
+    pub fn step(&mut self) {
+        for peer in self.peers.iter_mut() {
+            if peer.connecting && !peer.endpoint.is_connected() && self.now >= peer.next_connect {
+                debug!("{}: starting handshake", self.now);
+                peer.endpoint.connect(&mut peer.storage);
+                peer.next_connect = self.now + self.connect_interval;
+            }
+        }
+        for (peer, channel) in self.peers.iter_mut().zip(self.channels.iter_mut()) {
+            for frame in split_frames(&peer.storage.take_tx_data()) {
+                channel.send(frame, self.now, &mut self.rng);
+            }
+        }
+        for (channel, peer) in self.channels.iter_mut().zip(self.peers.iter_mut().rev()) {
+            for frame in channel.take_due(self.now) {
+                for byte in frame {
+                    if peer.endpoint.parse_byte(byte, &mut peer.storage) == ParseResult::UserPacket
+                    {
+                        peer.received.push(peer.storage.rx_data().to_vec());
+                    }
+                }
+            }
+        }
+        self.now += 1;
+    }
+
+    /// Runs the simulator for `ticks` ticks.
+    pub fn run_for(&mut self, ticks: Ticks) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    /// Runs the simulator until `done` returns true, giving up after
+    /// `max_ticks`. Returns whether `done` was satisfied.
+    pub fn run_until(
+        &mut self,
+        max_ticks: Ticks,
+        mut done: impl FnMut(&Simulator) -> bool,
+    ) -> bool {
+        for _ in 0..max_ticks {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use std::format;
+
+    fn connected(sim: &Simulator) -> bool {
+        sim.is_connected(Side::A) && sim.is_connected(Side::B) && sim.is_idle()
+    }
+
+    #[test]
+    fn test_split_frames() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&[SOF, 0xc0, 0x74, 0x36, SOF]);
+        bytes.extend_from_slice(&[SOF, 0x00, SOF, 0x01]);
+        assert_eq!(
+            split_frames(&bytes),
+            vec![
+                vec![SOF, 0xc0, 0x74, 0x36, SOF],
+                vec![SOF, 0x00, SOF],
+                vec![0x01]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_perfect_channel() {
+        setup_log();
+        let config = ChannelConfig {
+            latency: 3,
+            ..ChannelConfig::default()
+        };
+        let mut sim = Simulator::new(0, config, 64, 8);
+        sim.connect(Side::A);
+        // SYN0, SYN1 and SYN2 each take 3 ticks to cross, and the SYN1 and
+        // SYN2 are sent on the tick after the frame they respond to arrives.
+        assert!(sim.run_until(100, connected));
+        assert_eq!(sim.now(), 12);
+
+        sim.send(Side::A, b"Hello");
+        sim.send(Side::B, b"World");
+        sim.run_for(3);
+        assert!(sim.received(Side::B).is_empty());
+        sim.step();
+        assert_eq!(sim.received(Side::B), &[b"Hello".to_vec()]);
+        assert_eq!(sim.received(Side::A), &[b"World".to_vec()]);
+    }
+
+    #[test]
+    fn test_handshake_retry() {
+        setup_log();
+        let config = ChannelConfig {
+            drop: 0.5,
+            ..ChannelConfig::default()
+        };
+        let mut sim = Simulator::new(1, config, 64, 8);
+        sim.set_connect_interval(10);
+        sim.connect(Side::A);
+        sim.connect(Side::B);
+        assert!(sim.run_until(1000, connected));
+        assert!(sim.stats(Side::A).dropped + sim.stats(Side::B).dropped > 0);
+    }
+
+    #[test]
+    fn test_lossy_channel() {
+        setup_log();
+        let lossy = ChannelConfig {
+            corrupt: 0.002,
+            drop: 0.05,
+            duplicate: 0.05,
+            reorder: 0.05,
+            reorder_delay: 5,
+            latency: 2,
+            jitter: 3,
+        };
+        for seed in 0..20 {
+            let mut sim = Simulator::new(seed, lossy.clone(), 64, 32);
+            sim.connect(Side::A);
+            assert!(sim.run_until(10_000, connected), "seed {}", seed);
+
+            let mut sent_a = Vec::new();
+            let mut sent_b = Vec::new();
+            for i in 0..100 {
+                let data = format!("A{} {}", i, "x".repeat(i % 40)).into_bytes();
+                sim.send(Side::A, &data);
+                sent_a.push(data);
+                if i % 3 == 0 {
+                    let data = format!("B{}", i).into_bytes();
+                    sim.send(Side::B, &data);
+                    sent_b.push(data);
+                }
+                sim.run_for(4);
+
+                // Whatever has been delivered so far arrived exactly once
+                // and in order.
+                let received = sim.received(Side::B);
+                assert_eq!(received, &sent_a[..received.len()], "seed {}", seed);
+                let received = sim.received(Side::A);
+                assert_eq!(received, &sent_b[..received.len()], "seed {}", seed);
+            }
+
+            // SFP only recovers a lost frame when a later one shows up, so
+            // heal the channel and send one more packet in each direction.
+            sim.set_channel_config(ChannelConfig::default());
+            sim.run_for(50);
+            for (side, sent) in [(Side::A, &mut sent_a), (Side::B, &mut sent_b)].iter_mut() {
+                sim.send(*side, b"Last");
+                sent.push(b"Last".to_vec());
+            }
+            assert!(sim.run_until(1000, |sim| sim.is_idle()), "seed {}", seed);
+            assert_eq!(sim.received(Side::B), &sent_a[..], "seed {}", seed);
+            assert_eq!(sim.received(Side::A), &sent_b[..], "seed {}", seed);
+
+            let stats = sim.stats(Side::A);
+            assert!(stats.dropped > 0 && stats.corrupted > 0, "seed {}", seed);
+            assert!(stats.duplicated > 0 && stats.reordered > 0, "seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+use log::error;
+
+use crate::packet::{FrameType, PacketParser, PacketTypeResult};
+use crate::traits::{PacketBuffer, PacketQueue, PacketWriter};
+use crate::{ConnectState, EndPoint, ParseResult, Requests, Responder, Transmitter};
+
+#[derive(Debug, PartialEq)]
+pub enum TxError {
+    /// The link isn't connected, so the packet wasn't sent.
+    NotConnected,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    tx: Transmitter,
+    // Frames which the receive half wants the transmit half to send.
+    requests: Requests,
+}
+
+/// The state shared between the halves of a split EndPoint. Accesses are
+/// protected by a critical section, so the halves may be used from
+/// different interrupt priorities (e.g. the receive half from a UART ISR
+/// and the transmit half from the main loop).
+pub struct SplitState {
+    state: Mutex<Cell<State>>,
+}
+
+impl Default for SplitState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SplitState {
+    /// Creates the shared state. This is a const fn so that the state can
+    /// be placed in a static.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(State {
+                tx: Transmitter::new(),
+                requests: Requests::NONE,
+            })),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.with(|state| state.tx.connect_state == ConnectState::Connected)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        critical_section::with(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+            let result = f(&mut state);
+            cell.set(state);
+            result
+        })
+    }
+
+    // Takes the pending requests, along with the tx_seq to retransmit from.
+    fn take_requests(&self) -> (Requests, u8) {
+        self.with(|state| {
+            let requests = state.requests;
+            state.requests = Requests::NONE;
+            (requests, state.tx.tx_seq)
+        })
+    }
+}
+
+impl EndPoint {
+    /// Splits the EndPoint into a receive half and a transmit half, which
+    /// share the sequencing state through `shared`. Any state in `shared`
+    /// is replaced by the state of this EndPoint.
+    ///
+    /// The receive half never transmits. Frames that it needs to send
+    /// (handshake responses, NAKs and retransmissions) are recorded in the
+    /// shared state and sent the next time that the transmit half is
+    /// polled.
+    pub fn split(self, shared: &SplitState) -> (RxHalf<'_>, TxHalf<'_>) {
+        shared.with(|state| {
+            *state = State {
+                tx: self.tx,
+                requests: Requests::NONE,
+            }
+        });
+        (
+            RxHalf {
+                parser: self.rx.parser,
+                shared,
+            },
+            TxHalf { shared },
+        )
+    }
+}
+
+/// The receive half of a split EndPoint.
+pub struct RxHalf<'a> {
+    parser: PacketParser,
+    shared: &'a SplitState,
+}
+
+impl<'a> RxHalf<'a> {
+    pub fn is_connected(&self) -> bool {
+        self.shared.is_connected()
+    }
+
+    /// Parses a received byte. When UserPacket is returned, the packet is
+    /// available in `rx_buf`.
+    pub fn parse_byte(&mut self, byte: u8, rx_buf: &mut dyn PacketBuffer) -> ParseResult {
+        match self.parser.parse_byte(byte, rx_buf) {
+            PacketTypeResult::PacketReceived(packet_type) => self
+                .shared
+                .with(|state| state.tx.receive(packet_type, &mut state.requests)),
+            PacketTypeResult::AbortedPacket => ParseResult::AbortedPacket,
+            PacketTypeResult::PacketTooSmall => ParseResult::PacketTooSmall,
+            PacketTypeResult::CrcError(rcvd_crc) => ParseResult::CrcError(rcvd_crc),
+            PacketTypeResult::MoreDataNeeded => ParseResult::MoreDataNeeded,
+        }
+    }
+}
+
+// The history and writer used by the transmit half.
+struct SplitResponder<'b> {
+    tx_queue: &'b mut dyn PacketQueue,
+    writer: &'b mut dyn PacketWriter,
+}
+
+impl Responder for SplitResponder<'_> {
+    fn clear_history(&mut self) {
+        self.tx_queue.clear();
+    }
+
+    fn write_control(&mut self, header: u8) {
+        self.writer.write_packet_data(header, &[]);
+    }
+
+    fn history_len(&mut self) -> usize {
+        self.tx_queue.len()
+    }
+
+    fn retransmit(&mut self, offset: usize, header: u8) {
+        if let Some(packet) = self.tx_queue.get(offset) {
+            self.writer.write_packet_data(header, packet.data());
+        }
+    }
+}
+
+/// The transmit half of a split EndPoint.
+pub struct TxHalf<'a> {
+    shared: &'a SplitState,
+}
+
+impl<'a> TxHalf<'a> {
+    pub fn is_connected(&self) -> bool {
+        self.shared.is_connected()
+    }
+
+    /// Starts the handshake with the remote side.
+    pub fn connect(&mut self, tx_queue: &mut dyn PacketQueue, writer: &mut dyn PacketWriter) {
+        self.shared
+            .with(|state| state.tx.connect(&mut state.requests));
+        self.poll(tx_queue, writer);
+    }
+
+    /// Sends any frames which have been requested by the receive half. This
+    /// should be called regularly from thread context (e.g. whenever the
+    /// receive half has processed some data).
+    pub fn poll(&mut self, tx_queue: &mut dyn PacketQueue, writer: &mut dyn PacketWriter) {
+        let (requests, tx_seq) = self.shared.take_requests();
+        requests.send(tx_seq, &mut SplitResponder { tx_queue, writer });
+    }
+
+    /// Sends a user packet, after sending any frames which have been
+    /// requested by the receive half.
+    pub fn write_packet(
+        &mut self,
+        data: &[u8],
+        tx_queue: &mut dyn PacketQueue,
+        writer: &mut dyn PacketWriter,
+    ) -> Result<(), TxError> {
+        self.write_packet_vectored(&[data], tx_queue, writer)
+    }
+
+    /// Sends a user packet whose payload is the concatenation of `bufs`.
+    pub fn write_packet_vectored(
+        &mut self,
+        bufs: &[&[u8]],
+        tx_queue: &mut dyn PacketQueue,
+        writer: &mut dyn PacketWriter,
+    ) -> Result<(), TxError> {
+        // Taking the requests and allocating the sequence number in the
+        // same critical section means that a SYN0 arriving in between can't
+        // clear the history after our packet has been added to it.
+        let (requests, tx_seq, seq) = self.shared.with(|state| {
+            let requests = state.requests;
+            state.requests = Requests::NONE;
+            let tx_seq = state.tx.tx_seq;
+            let seq = if state.tx.connect_state == ConnectState::Connected {
+                Some(state.tx.allocate_seq())
+            } else {
+                None
+            };
+            (requests, tx_seq, seq)
+        });
+        requests.send(
+            tx_seq,
+            &mut SplitResponder {
+                tx_queue: &mut *tx_queue,
+                writer: &mut *writer,
+            },
+        );
+        let seq = match seq {
+            Some(seq) => seq,
+            None => {
+                error!("Not connected");
+                return Err(TxError::NotConnected);
+            }
+        };
+        tx_queue.next().store_data_vectored(bufs);
+        writer.write_packet_data_vectored(FrameType::USR as u8 | seq, bufs);
+        Ok(())
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::SOF;
+    use crate::vecstorage::{VecPacketBuffer, VecPacketQueue, VecStorage};
+    use std::vec::Vec;
+
+    // The storage used by each half of the split EndPoint.
+    struct SplitStorage {
+        rx_buf: VecPacketBuffer,
+        tx_queue: VecPacketQueue,
+        tx_data: Vec<u8>,
+    }
+
+    impl SplitStorage {
+        fn new() -> Self {
+            Self {
+                rx_buf: VecPacketBuffer::new(66),
+                tx_queue: VecPacketQueue::new(4, 64),
+                tx_data: Vec::new(),
+            }
+        }
+    }
+
+    // Feeds the bytes written by the regular EndPoint into the receive
+    // half, and returns the user packets received.
+    fn pump_to_rx(from: &mut VecStorage, rx: &mut RxHalf, st: &mut SplitStorage) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        for byte in from.take_tx_data() {
+            if rx.parse_byte(byte, &mut st.rx_buf) == ParseResult::UserPacket {
+                packets.push(st.rx_buf.data().to_vec());
+            }
+        }
+        packets
+    }
+
+    // Feeds the bytes written by the transmit half into the regular
+    // EndPoint, and returns the user packets received.
+    fn pump_to_ep(st: &mut SplitStorage, ep: &mut EndPoint, to: &mut VecStorage) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        for byte in core::mem::take(&mut st.tx_data) {
+            if ep.parse_byte(byte, to) == ParseResult::UserPacket {
+                packets.push(to.rx_data().to_vec());
+            }
+        }
+        packets
+    }
+
+    static SHARED: SplitState = SplitState::new();
+
+    #[test]
+    fn test_split() {
+        setup_log();
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut st = SplitStorage::new();
+        let (mut rx, mut tx) = EndPoint::new().split(&SHARED);
+
+        assert_eq!(
+            tx.write_packet(b"Early", &mut st.tx_queue, &mut st.tx_data),
+            Err(TxError::NotConnected)
+        );
+
+        // The split side initiates the handshake. The responses from the
+        // receive half are only sent once the transmit half is polled.
+        tx.connect(&mut st.tx_queue, &mut st.tx_data);
+        pump_to_ep(&mut st, &mut ep1, &mut storage1);
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        assert!(rx.is_connected());
+        assert!(st.tx_data.is_empty());
+        tx.poll(&mut st.tx_queue, &mut st.tx_data);
+        assert_eq!(st.tx_data, &[SOF, 0xc2, 0x66, 0x15, SOF]);
+        pump_to_ep(&mut st, &mut ep1, &mut storage1);
+        assert!(ep1.is_connected());
+
+        ep1.write_packet(b"Ping", &mut storage1);
+        assert_eq!(
+            pump_to_rx(&mut storage1, &mut rx, &mut st),
+            vec![b"Ping".to_vec()]
+        );
+        tx.write_packet(b"Pong", &mut st.tx_queue, &mut st.tx_data)
+            .unwrap();
+        assert_eq!(
+            pump_to_ep(&mut st, &mut ep1, &mut storage1),
+            vec![b"Pong".to_vec()]
+        );
+
+        // The remote side restarting the handshake disconnects us until
+        // the transmit half sends the SYN1.
+        ep1.connect(&mut storage1);
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        assert!(!tx.is_connected());
+        tx.poll(&mut st.tx_queue, &mut st.tx_data);
+        pump_to_ep(&mut st, &mut ep1, &mut storage1);
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        assert!(tx.is_connected());
+        assert!(ep1.is_connected());
+    }
+
+    #[test]
+    fn test_split_nak() {
+        setup_log();
+        let shared = SplitState::new();
+        let mut storage1 = VecStorage::new(64, 4);
+        let mut ep1 = EndPoint::new();
+        let mut st = SplitStorage::new();
+        let (mut rx, mut tx) = EndPoint::new().split(&shared);
+
+        ep1.connect(&mut storage1);
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        tx.poll(&mut st.tx_queue, &mut st.tx_data);
+        pump_to_ep(&mut st, &mut ep1, &mut storage1);
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        assert!(ep1.is_connected());
+        assert!(rx.is_connected());
+
+        // Lose the first packet sent by the transmit half.
+        for data in [&b"One"[..], b"Two", b"Three"].iter() {
+            tx.write_packet(data, &mut st.tx_queue, &mut st.tx_data)
+                .unwrap();
+            if *data == b"One" {
+                st.tx_data.clear();
+            }
+        }
+        assert!(pump_to_ep(&mut st, &mut ep1, &mut storage1).is_empty());
+
+        // Both NAKs are received before the transmit half gets to run, so
+        // the history is only retransmitted once.
+        pump_to_rx(&mut storage1, &mut rx, &mut st);
+        tx.poll(&mut st.tx_queue, &mut st.tx_data);
+        assert_eq!(
+            pump_to_ep(&mut st, &mut ep1, &mut storage1),
+            vec![b"One".to_vec(), b"Two".to_vec(), b"Three".to_vec()]
+        );
+
+        // Lose a packet sent to the receive half. The NAK goes out with the
+        // next packet written by the transmit half.
+        ep1.write_packet(b"Four", &mut storage1);
+        storage1.take_tx_data();
+        ep1.write_packet(b"Five", &mut storage1);
+        assert!(pump_to_rx(&mut storage1, &mut rx, &mut st).is_empty());
+        tx.write_packet(b"Six", &mut st.tx_queue, &mut st.tx_data)
+            .unwrap();
+        assert_eq!(&st.tx_data[..5], &[SOF, 0x80, 0x70, 0x74, SOF]);
+        assert_eq!(
+            pump_to_ep(&mut st, &mut ep1, &mut storage1),
+            vec![b"Six".to_vec()]
+        );
+        assert_eq!(
+            pump_to_rx(&mut storage1, &mut rx, &mut st),
+            vec![b"Four".to_vec(), b"Five".to_vec()]
+        );
+    }
+}
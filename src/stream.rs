@@ -0,0 +1,365 @@
+use core::cmp::min;
+use log::debug;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::vec::Vec;
+
+use crate::vecstorage::VecStorage;
+use crate::{EndPoint, ParseResult};
+
+const DEFAULT_PACKET_SIZE: usize = 256;
+const DEFAULT_HISTORY_LEN: usize = 8;
+const DEFAULT_CONNECT_ATTEMPTS: usize = 10;
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// A blocking reliable link over any Read + Write transport (a TcpStream,
+/// a serial port, etc).
+///
+/// Read timeouts configured on the transport are used to drive the
+/// handshake and retransmission. Each time a read times out while the link
+/// isn't connected, the handshake is restarted. When a read times out while
+/// connected, the packets sent since the remote side was last heard from
+/// are retransmitted (up to the history length), since the remote side
+/// can't NAK a packet that was lost with nothing sent after it. This is
+/// repeated on each timeout until something is received. Without a read
+/// timeout such a packet is only recovered once another packet is sent.
+pub struct SfpStream<T> {
+    transport: T,
+    endpoint: EndPoint,
+    storage: VecStorage,
+    read_buf: Vec<u8>,
+    received: VecDeque<Vec<u8>>,
+    connect_attempts: usize,
+    // The number of packets sent since the remote side was last heard from.
+    unacked: usize,
+}
+
+impl<T: Read + Write> SfpStream<T> {
+    pub fn new(transport: T) -> Self {
+        Self::with_capacity(transport, DEFAULT_PACKET_SIZE, DEFAULT_HISTORY_LEN)
+    }
+
+    /// Creates a stream which supports user packets of up to
+    /// `max_packet_size` bytes and keeps the `history_len` most recently sent
+    /// packets for retransmission.
+    pub fn with_capacity(transport: T, max_packet_size: usize, history_len: usize) -> Self {
+        Self {
+            transport,
+            endpoint: EndPoint::new(),
+            storage: VecStorage::new(max_packet_size, history_len),
+            read_buf: vec![0; 1024],
+            received: VecDeque::new(),
+            connect_attempts: DEFAULT_CONNECT_ATTEMPTS,
+            unacked: 0,
+        }
+    }
+
+    /// Sets the number of times that `connect` will send the handshake
+    /// before giving up.
+    pub fn set_connect_attempts(&mut self, attempts: usize) {
+        self.connect_attempts = attempts;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.endpoint.is_connected()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    fn flush_tx(&mut self) -> io::Result<()> {
+        if !self.storage.tx_data().is_empty() {
+            let data = self.storage.take_tx_data();
+            self.transport.write_all(&data)?;
+            self.transport.flush()?;
+        }
+        Ok(())
+    }
+
+    fn start_handshake(&mut self) -> io::Result<()> {
+        debug!("Starting handshake");
+        self.endpoint.connect(&mut self.storage);
+        self.unacked = 0;
+        self.flush_tx()
+    }
+
+    fn retransmit(&mut self) -> io::Result<()> {
+        if self.unacked > 0 {
+            debug!("Retransmitting {} packets", self.unacked);
+            self.endpoint
+                .retransmit_last(self.unacked, &mut self.storage);
+        }
+        self.flush_tx()
+    }
+
+    // Does a single read from the transport, and processes the bytes read.
+    fn fill(&mut self) -> io::Result<()> {
+        let bytes_read = self.transport.read(&mut self.read_buf)?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.unacked = 0;
+        let mut idx = 0;
+        while idx < bytes_read {
+            let (consumed, parse_result) = self
+                .endpoint
+                .parse_bytes(&self.read_buf[idx..bytes_read], &mut self.storage);
+            idx += consumed;
+            if parse_result == ParseResult::UserPacket {
+                self.received.push_back(self.storage.rx_data().to_vec());
+            }
+        }
+        self.flush_tx()
+    }
+
+    /// Performs the handshake with the remote side, blocking until the link
+    /// is connected. The handshake is resent each time a read times out. A
+    /// TimedOut error is returned if the remote side doesn't respond after
+    /// the configured number of attempts.
+    pub fn connect(&mut self) -> io::Result<()> {
+        self.start_handshake()?;
+        let mut attempts = 1;
+        while !self.is_connected() {
+            match self.fill() {
+                Ok(()) => {}
+                Err(err) if is_timeout(&err) => {
+                    if attempts >= self.connect_attempts {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no response to handshake",
+                        ));
+                    }
+                    attempts += 1;
+                    self.start_handshake()?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as a user packet.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.is_connected() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.endpoint.write_packet(data, &mut self.storage);
+        self.unacked += 1;
+        self.flush_tx()
+    }
+
+    /// Blocks until a user packet is received and copies it into `buf`,
+    /// returning the length of the packet. If the packet is larger than
+    /// `buf` then the excess bytes are discarded.
+    ///
+    /// If the transport has a read timeout, then the timeout error is
+    /// returned. If the link isn't connected when the timeout occurs then
+    /// the handshake is restarted first, otherwise any packets sent since
+    /// the remote side was last heard from are retransmitted.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(packet) = self.received.pop_front() {
+                let copy_len = min(packet.len(), buf.len());
+                buf[..copy_len].copy_from_slice(&packet[..copy_len]);
+                return Ok(copy_len);
+            }
+            if let Err(err) = self.fill() {
+                if is_timeout(&err) {
+                    if self.is_connected() {
+                        self.retransmit()?;
+                    } else {
+                        self.start_handshake()?;
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::setup_log;
+    use crate::traits::SOF;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_echo() {
+        setup_log();
+        let (client, server) = tcp_pair();
+
+        let echo = thread::spawn(move || {
+            let mut stream = SfpStream::new(server);
+            stream.connect().unwrap();
+            let mut buf = [0u8; 256];
+            loop {
+                match stream.recv(&mut buf) {
+                    Ok(len) => stream.send(&buf[..len]).unwrap(),
+                    Err(err) => {
+                        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let mut stream = SfpStream::new(client);
+        stream.connect().unwrap();
+        let mut buf = [0u8; 256];
+        for msg in [&b"Hello"[..], b"\x7e\x7d", b"World"].iter() {
+            stream.send(msg).unwrap();
+            let len = stream.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], *msg);
+        }
+
+        // Packets which don't fit are truncated.
+        stream.send(b"Truncated").unwrap();
+        assert_eq!(stream.recv(&mut buf[..5]).unwrap(), 5);
+        assert_eq!(&buf[..5], b"Trunc");
+
+        drop(stream);
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        setup_log();
+        let (client, mut server) = tcp_pair();
+        client
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+
+        let mut stream = SfpStream::new(client);
+        stream.set_connect_attempts(3);
+        let err = stream.connect().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The SYN0 should have been sent once per attempt.
+        let mut buf = [0u8; 15];
+        server.read_exact(&mut buf).unwrap();
+        let syn0 = [SOF, 0xc0, 0x74, 0x36, SOF];
+        assert_eq!(&buf[..5], &syn0);
+        assert_eq!(&buf[5..10], &syn0);
+        assert_eq!(&buf[10..], &syn0);
+
+        assert_eq!(
+            stream.send(b"Nope").unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+
+    // Wraps a TcpStream, and discards the next write when `drop_next` is
+    // set. `writes` counts the writes, including discarded ones.
+    struct LossyStream {
+        inner: TcpStream,
+        drop_next: bool,
+        writes: usize,
+    }
+
+    impl Read for LossyStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for LossyStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.drop_next {
+                self.drop_next = false;
+                return Ok(buf.len());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_lost_packet() {
+        setup_log();
+        let (client, server) = tcp_pair();
+        for stream in [&client, &server].iter() {
+            stream
+                .set_read_timeout(Some(Duration::from_millis(20)))
+                .unwrap();
+        }
+        let remote = thread::spawn(move || {
+            let mut stream = SfpStream::new(server);
+            stream.connect().unwrap();
+            stream
+        });
+        let mut stream = SfpStream::new(LossyStream {
+            inner: client,
+            drop_next: false,
+            writes: 0,
+        });
+        stream.connect().unwrap();
+        let mut remote = remote.join().unwrap();
+        let mut buf = [0u8; 16];
+
+        // Make sure the end of both handshakes has arrived, so that it
+        // doesn't get mixed up with the lost packet below.
+        stream.send(b"Ready").unwrap();
+        let len = remote.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"Ready");
+        remote.send(b"Ready").unwrap();
+        let len = stream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"Ready");
+
+        // Nothing follows the lost packet, so the remote side can't NAK it.
+        stream.get_mut().drop_next = true;
+        stream.send(b"Lost").unwrap();
+        assert!(is_timeout(&remote.recv(&mut buf).unwrap_err()));
+
+        // It gets retransmitted when our read times out.
+        assert!(is_timeout(&stream.recv(&mut buf).unwrap_err()));
+        let len = remote.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"Lost");
+
+        // We haven't heard from the remote side, so the packet is
+        // retransmitted on every timeout. The duplicates are ignored.
+        let writes = stream.get_ref().writes;
+        assert!(is_timeout(&stream.recv(&mut buf).unwrap_err()));
+        assert_eq!(stream.get_ref().writes, writes + 1);
+        assert!(is_timeout(&remote.recv(&mut buf).unwrap_err()));
+
+        // Once the remote side sends something the retransmissions stop.
+        remote.send(b"Got it").unwrap();
+        let len = stream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"Got it");
+        let writes = stream.get_ref().writes;
+        assert!(is_timeout(&stream.recv(&mut buf).unwrap_err()));
+        assert_eq!(stream.get_ref().writes, writes);
+    }
+}
@@ -1,13 +1,13 @@
-use core::cmp::{max, min};
+use core::cmp::min;
 use log::{error, info, warn};
 use pretty_hex::*;
-use simple_logger;
 use std::sync::Once;
 use std::vec::Vec;
 
 use super::crc::Crc;
 use super::rawpacket::{RawPacketParser, RawParseResult};
 use super::traits::{PacketBuffer, PacketQueue, PacketWriter, Storage};
+use super::{EndPoint, ParseResult};
 
 static INIT: Once = Once::new();
 
@@ -98,11 +98,6 @@ impl PacketQueue for TestPacketQueue {
     /// Sets the number of packets currently in the queue.
     fn set_len(&mut self, len: usize) {
         self.len = min(len, QUEUE_SIZE);
-        self.idx = if self.len > 0 {
-            min(self.idx, self.len - 1)
-        } else {
-            0
-        }
     }
 
     /// Returns the index of the most recently added packet to the queue.
@@ -113,15 +108,13 @@ impl PacketQueue for TestPacketQueue {
     /// Sets the index of the nmost recently added packet to the queue.
     fn set_idx(&mut self, idx: usize) {
         self.idx = min(idx, QUEUE_SIZE - 1);
-        self.len = max(self.len, self.idx + 1);
     }
 
     /// Returns the i'th packet from the queue.
     fn packet(&mut self, idx: usize) -> Option<&mut dyn PacketBuffer> {
-        if idx < self.len {
-            Some(&mut self.packet[idx])
-        } else {
-            None
+        match self.packet.get_mut(idx) {
+            Some(packet) => Some(packet),
+            None => None,
         }
     }
 }
@@ -192,11 +185,11 @@ pub fn parse_bytes_as_packet(
             let mut vec = Vec::new();
             vec.push(header);
             vec.extend_from_slice(rx_packet.data());
-            return vec;
+            vec
         }
         _ => {
             error!("{:?}", parse_result);
-            return Vec::new();
+            Vec::new()
         }
     }
 }
@@ -245,3 +238,55 @@ impl TestStorage {
         self.tx_data().to_vec()
     }
 }
+
+impl EndPoint {
+    // Parse a bunch of bytes and return the first return code that isn't
+    // MoreDataNeeded. This means that this function will parse at most one
+    // error or packet from the input stream, which is fine for testing.
+
+    // bytes, rx_packet, writer
+    pub fn parse_packet(&mut self, bytes: &[u8], storage: &mut dyn Storage) -> ParseResult {
+        storage.tx_writer().start_write(); // Clears the outout buffer.
+        for byte in bytes.iter() {
+            let parse_result = self.parse_byte(*byte, storage);
+            match parse_result {
+                ParseResult::UserPacket => {
+                    return ParseResult::UserPacket;
+                }
+
+                ParseResult::MoreDataNeeded => {
+                    continue;
+                }
+
+                ParseResult::AbortedPacket => {
+                    return ParseResult::AbortedPacket;
+                }
+
+                ParseResult::PacketTooSmall => {
+                    return ParseResult::PacketTooSmall;
+                }
+
+                ParseResult::CrcError(rcvd_crc) => {
+                    return ParseResult::CrcError(rcvd_crc);
+                }
+            }
+        }
+        ParseResult::MoreDataNeeded
+    }
+}
+
+// Runs the connection handshake between two endpoints, passing each of the
+// generated control packets from one side to the other.
+pub fn connect_endpoints(
+    ep1: &mut EndPoint,
+    storage1: &mut TestStorage,
+    ep2: &mut EndPoint,
+    storage2: &mut TestStorage,
+) {
+    ep1.connect(storage1);
+    ep2.parse_packet(storage1.tx_data(), storage2);
+    ep1.parse_packet(storage2.tx_data(), storage1);
+    ep2.parse_packet(storage1.tx_data(), storage2);
+    assert!(ep1.is_connected());
+    assert!(ep2.is_connected());
+}
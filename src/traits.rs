@@ -1,10 +1,10 @@
 use core::cmp::min;
 use core::fmt;
-use core::mem::size_of;
 use log::info;
+use memchr::memchr2;
 use pretty_hex::*;
 
-use crate::crc::{Crc, CrcAccum};
+use crate::crc::{Crc, CrcAccum, CRC_LEN};
 
 pub const SOF: u8 = 0x7e; // Start of Frame
 pub const ESC: u8 = 0x7d;
@@ -39,6 +39,24 @@ pub trait PacketBuffer {
         self.set_len(copy_len);
     }
 
+    /// Appends the indicated data to the buffer. Any data which doesn't fit
+    /// is discarded.
+    fn append_data(&mut self, data: &[u8]) {
+        let len = self.len();
+        let copy_len = min(data.len(), self.capacity() - len);
+        self.data_mut()[len..len + copy_len].copy_from_slice(&data[..copy_len]);
+        self.set_len(len + copy_len);
+    }
+
+    /// Copies the concatenation of `bufs` into the buffer. Any data which
+    /// doesn't fit is discarded.
+    fn store_data_vectored(&mut self, bufs: &[&[u8]]) {
+        self.reset();
+        for buf in bufs.iter() {
+            self.append_data(buf);
+        }
+    }
+
     /// Determines if the current buffer is currently empty or not.
     fn is_empty(&self) -> bool {
         self.len() == 0
@@ -51,6 +69,7 @@ pub trait PacketBuffer {
 
     /// Appends a byte to the end of the packet buffer. This function will
     /// return an error result if the packet buffer is full.
+    #[allow(clippy::result_unit_err)]
     fn append(&mut self, byte: u8) -> Result<(), ()> {
         let len = self.len();
         if len < self.capacity() {
@@ -67,12 +86,12 @@ pub trait PacketBuffer {
     /// location that the MSB)
     fn remove_crc(&mut self) -> CrcAccum {
         let mut len = self.len();
-        if len < size_of::<CrcAccum>() {
+        if len < CRC_LEN {
             return 0;
         }
 
         // LSB is transmitted first
-        len -= 2;
+        len -= CRC_LEN;
         let data = self.data();
         let crc = ((data[len + 1] as CrcAccum) << 8) | (data[len] as CrcAccum);
         self.set_len(len);
@@ -99,22 +118,38 @@ pub trait PacketWriter {
     /// Called to write some data (not necessarily a complete packet) to the hardware.
     fn write_byte(&mut self, byte: u8);
 
+    /// Called to write a run of bytes which don't need escaping. Writers
+    /// which can copy the bytes in bulk should override this.
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.write_byte(*byte);
+        }
+    }
+
     /// Called at the end of the writing a packet. Allows the driver to flush a
     /// buffer if a buffered implementation is used.
     fn end_write(&mut self) {}
 
     /// Called to write an entire packet
     fn write_packet_data(&mut self, header: u8, bytes: &[u8]) {
+        self.write_packet_data_vectored(header, &[bytes]);
+    }
+
+    /// Called to write an entire packet whose payload is the concatenation
+    /// of `bufs`. The slices are escaped and CRC'd as a single payload.
+    fn write_packet_data_vectored(&mut self, header: u8, bufs: &[&[u8]]) {
         info!(
             "write_packet_data header: 0x{:02x} len: {}",
             header,
-            bytes.len()
+            bufs.iter().map(|buf| buf.len()).sum::<usize>()
         );
         let mut crc = Crc::new();
         self.start_write();
         self.write_byte(SOF);
         self.write_escaped_byte(&mut crc, header);
-        self.write_escaped_bytes(&mut crc, bytes);
+        for buf in bufs.iter() {
+            self.write_escaped_bytes(&mut crc, buf);
+        }
         self.write_crc(&mut crc);
         self.write_byte(SOF);
         self.end_write();
@@ -129,9 +164,17 @@ pub trait PacketWriter {
     }
 
     fn write_escaped_bytes(&mut self, crc: &mut Crc, bytes: &[u8]) {
-        for byte in bytes {
-            self.write_escaped_byte(crc, *byte);
+        // Write the runs of bytes between the ones which need escaping in
+        // bulk.
+        let mut rest = bytes;
+        while let Some(idx) = memchr2(SOF, ESC, rest) {
+            crc.accum_bytes(&rest[..idx]);
+            self.write_bytes(&rest[..idx]);
+            self.write_escaped_byte(crc, rest[idx]);
+            rest = &rest[idx + 1..];
         }
+        crc.accum_bytes(rest);
+        self.write_bytes(rest);
     }
 
     fn write_escaped_byte(&mut self, crc: &mut Crc, byte: u8) {
@@ -158,6 +201,11 @@ pub trait PacketQueue {
     /// Sets the number of packets currently in the queue.
     fn set_len(&mut self, len: usize);
 
+    /// Determines if the queue is currently empty or not.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     // Returns the index of the most recently added packet to the queue.
     fn idx(&self) -> usize;
 
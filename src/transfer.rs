@@ -0,0 +1,842 @@
+use core::cmp::min;
+use core::convert::TryInto;
+use log::{debug, warn};
+
+use crate::crc::{Crc32, Crc32Accum};
+use crate::traits::Storage;
+use crate::EndPoint;
+
+// Each transfer message starts with a kind byte.
+//
+// START  (sender -> receiver): len (u32) crc32 (u32) name...
+// ACCEPT (receiver -> sender): offset (u32)
+// CHUNK  (sender -> receiver): offset (u32) data...
+// END    (sender -> receiver):
+// DONE   (receiver -> sender): status (u8)
+//
+// All multi-byte values are little endian.
+const XFER_START: u8 = 0;
+const XFER_ACCEPT: u8 = 1;
+const XFER_CHUNK: u8 = 2;
+const XFER_END: u8 = 3;
+const XFER_DONE: u8 = 4;
+
+const STATUS_OK: u8 = 0;
+
+const START_HEADER_SIZE: usize = 9;
+
+/// Size of the header sent in front of the data in each CHUNK message.
+pub const CHUNK_HEADER_SIZE: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferError {
+    /// The EndPoint isn't connected.
+    NotConnected,
+    /// The CRC-32 of the received blob didn't match the CRC-32 of the sent blob.
+    CrcMismatch,
+    /// The amount of data received didn't match the length of the blob.
+    LengthMismatch,
+    /// The sink reported an error while storing the blob.
+    Sink,
+    /// The blob's name doesn't fit in a packet along with the START header.
+    NameTooLong,
+    /// The chunk buffer doesn't fit in a packet along with the CHUNK header.
+    ChunkTooLong,
+    /// The receiver got data without a preceding START.
+    NotStarted,
+    /// The remote side reported an unrecognized error status.
+    Remote(u8),
+}
+
+impl TransferError {
+    fn to_status(self) -> u8 {
+        match self {
+            TransferError::NotConnected => 1,
+            TransferError::CrcMismatch => 2,
+            TransferError::LengthMismatch => 3,
+            TransferError::Sink => 4,
+            TransferError::NameTooLong => 5,
+            TransferError::ChunkTooLong => 6,
+            TransferError::NotStarted => 7,
+            TransferError::Remote(status) => status,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1 => TransferError::NotConnected,
+            2 => TransferError::CrcMismatch,
+            3 => TransferError::LengthMismatch,
+            4 => TransferError::Sink,
+            5 => TransferError::NameTooLong,
+            6 => TransferError::ChunkTooLong,
+            7 => TransferError::NotStarted,
+            _ => TransferError::Remote(status),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferEvent {
+    /// `offset` bytes of the `len` byte blob have been transferred.
+    Progress { offset: u32, len: u32 },
+    /// The entire blob was transferred and its CRC-32 verified.
+    Complete,
+    /// The transfer failed.
+    Failed(TransferError),
+}
+
+/// Provides the data for a blob being sent.
+pub trait BlobSource {
+    /// Returns the total length of the blob.
+    fn len(&self) -> u32;
+
+    /// Copies bytes starting at `offset` into `buf`, and returns the number
+    /// of bytes copied.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> usize;
+
+    /// Determines if the blob is empty or not.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BlobSource for &[u8] {
+    fn len(&self) -> u32 {
+        <[u8]>::len(self) as u32
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> usize {
+        let offset = min(offset as usize, <[u8]>::len(self));
+        let copy_len = min(buf.len(), <[u8]>::len(self) - offset);
+        buf[..copy_len].copy_from_slice(&self[offset..offset + copy_len]);
+        copy_len
+    }
+}
+
+/// Stores the data for a blob being received.
+pub trait BlobSink {
+    /// Called when a new blob starts to be received.
+    fn start(&mut self, name: &[u8], len: u32) -> Result<(), TransferError>;
+
+    /// Called to store the data starting at `offset`.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), TransferError>;
+
+    /// Called once all of the data has been received. `result` indicates
+    /// whether the blob was verified or not.
+    fn finish(&mut self, result: Result<(), TransferError>);
+}
+
+fn get_u32(data: &[u8], idx: usize) -> Option<u32> {
+    let bytes = data.get(idx..idx + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[derive(Debug, PartialEq)]
+enum SenderState {
+    Idle,
+    WaitAccept,
+    Sending,
+    WaitDone,
+    Done,
+}
+
+/// Sends a named blob to a BlobReceiver on the other side of the link.
+///
+/// Call `start` to begin the transfer, `poll` each time the link can accept
+/// another packet, and pass any user packets received to `handle_packet`.
+/// If the link is reconnected then calling `start` again will resume the
+/// transfer from wherever the receiver left off.
+pub struct BlobSender<'a> {
+    name: &'a [u8],
+    source: &'a mut dyn BlobSource,
+    chunk_buf: &'a mut [u8],
+    len: u32,
+    crc: Crc32Accum,
+    offset: u32,
+    state: SenderState,
+}
+
+impl<'a> BlobSender<'a> {
+    /// Creates a new sender. `chunk_buf` determines the maximum amount of
+    /// data sent in each chunk. The chunk data plus CHUNK_HEADER_SIZE needs
+    /// to fit in a packet.
+    pub fn new(name: &'a [u8], source: &'a mut dyn BlobSource, chunk_buf: &'a mut [u8]) -> Self {
+        let len = source.len();
+        let mut crc = Crc32::new();
+        let mut offset = 0;
+        while offset < len {
+            let want = min(chunk_buf.len(), (len - offset) as usize);
+            let bytes_read = source.read(offset, &mut chunk_buf[..want]);
+            if bytes_read == 0 {
+                break;
+            }
+            crc.accum_bytes(&chunk_buf[..bytes_read]);
+            offset += bytes_read as u32;
+        }
+        Self {
+            name,
+            source,
+            chunk_buf,
+            len,
+            crc: crc.crc(),
+            offset: 0,
+            state: SenderState::Idle,
+        }
+    }
+
+    /// Returns the progress of the transfer.
+    pub fn progress(&self) -> TransferEvent {
+        TransferEvent::Progress {
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+
+    /// Determines if the transfer has finished (successfully or not).
+    pub fn is_done(&self) -> bool {
+        self.state == SenderState::Done
+    }
+
+    /// Starts (or restarts) the transfer by announcing the blob to the
+    /// receiver. The receiver replies with the offset to start sending from.
+    /// Fails with NameTooLong if the name doesn't fit in a packet, and with
+    /// ChunkTooLong if a full chunk doesn't fit in a packet.
+    pub fn start(
+        &mut self,
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Result<(), TransferError> {
+        if !endpoint.is_connected() {
+            return Err(TransferError::NotConnected);
+        }
+        if let Some(packet) = storage.tx_queue().packet(0) {
+            if START_HEADER_SIZE + self.name.len() > packet.capacity() {
+                return Err(TransferError::NameTooLong);
+            }
+            if CHUNK_HEADER_SIZE + self.chunk_buf.len() > packet.capacity() {
+                return Err(TransferError::ChunkTooLong);
+            }
+        }
+        let mut hdr = [XFER_START; START_HEADER_SIZE];
+        hdr[1..5].copy_from_slice(&self.len.to_le_bytes());
+        hdr[5..9].copy_from_slice(&self.crc.to_le_bytes());
+        endpoint.write_packet_vectored(&[&hdr, self.name], storage);
+        self.state = SenderState::WaitAccept;
+        Ok(())
+    }
+
+    /// Sends the next chunk of the blob, or the end of transfer message once
+    /// all of the data has been sent. Returns the progress after a chunk
+    /// is sent.
+    pub fn poll(
+        &mut self,
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Option<TransferEvent> {
+        if self.state != SenderState::Sending || !endpoint.is_connected() {
+            return None;
+        }
+        if self.offset >= self.len {
+            endpoint.write_packet(&[XFER_END], storage);
+            self.state = SenderState::WaitDone;
+            return None;
+        }
+        let want = min(self.chunk_buf.len(), (self.len - self.offset) as usize);
+        let bytes_read = self.source.read(self.offset, &mut self.chunk_buf[..want]);
+        let mut hdr = [XFER_CHUNK; CHUNK_HEADER_SIZE];
+        hdr[1..5].copy_from_slice(&self.offset.to_le_bytes());
+        endpoint.write_packet_vectored(&[&hdr, &self.chunk_buf[..bytes_read]], storage);
+        self.offset += bytes_read as u32;
+        if bytes_read == 0 {
+            // The source ran out of data early. Sending END will let the
+            // receiver report the length mismatch.
+            self.offset = self.len;
+        }
+        Some(self.progress())
+    }
+
+    /// Processes a user packet received from the BlobReceiver.
+    pub fn handle_packet(&mut self, data: &[u8]) -> Option<TransferEvent> {
+        match data.first() {
+            Some(&XFER_ACCEPT) => {
+                let offset = get_u32(data, 1)?;
+                if self.state == SenderState::Done {
+                    return None;
+                }
+                debug!("Transfer accepted at offset {}", offset);
+                self.offset = min(offset, self.len);
+                self.state = SenderState::Sending;
+                Some(self.progress())
+            }
+            Some(&XFER_DONE) => {
+                let status = *data.get(1)?;
+                self.state = SenderState::Done;
+                if status == STATUS_OK {
+                    Some(TransferEvent::Complete)
+                } else {
+                    Some(TransferEvent::Failed(TransferError::from_status(status)))
+                }
+            }
+            _ => {
+                warn!("Dropping unexpected transfer packet");
+                None
+            }
+        }
+    }
+}
+
+/// Receives blobs sent by a BlobSender and writes them into a BlobSink.
+///
+/// The receiver remembers how much of the current blob it has received, so
+/// if the sender restarts the same blob (e.g. after the link reconnects)
+/// the transfer resumes from where it left off. Once a blob has finished,
+/// the receiver repeats its DONE status in reply to any retransmitted CHUNK
+/// or END, so a sender which missed the original reply still finishes.
+pub struct BlobReceiver<'a> {
+    sink: &'a mut dyn BlobSink,
+    active: bool,
+    last_status: Option<u8>,
+    name_crc: Crc32Accum,
+    len: u32,
+    expected_crc: Crc32Accum,
+    offset: u32,
+    crc: Crc32,
+}
+
+impl<'a> BlobReceiver<'a> {
+    pub fn new(sink: &'a mut dyn BlobSink) -> Self {
+        Self {
+            sink,
+            active: false,
+            last_status: None,
+            name_crc: 0,
+            len: 0,
+            expected_crc: 0,
+            offset: 0,
+            crc: Crc32::new(),
+        }
+    }
+
+    /// Returns the progress of the current transfer.
+    pub fn progress(&self) -> TransferEvent {
+        TransferEvent::Progress {
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+
+    fn send_accept(&mut self, endpoint: &mut EndPoint, storage: &mut dyn Storage) {
+        let offset = self.offset.to_le_bytes();
+        endpoint.write_packet_vectored(&[&[XFER_ACCEPT], &offset], storage);
+    }
+
+    fn finish(
+        &mut self,
+        result: Result<(), TransferError>,
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> TransferEvent {
+        self.active = false;
+        self.sink.finish(result);
+        let (status, event) = match result {
+            Ok(()) => (STATUS_OK, TransferEvent::Complete),
+            Err(err) => (err.to_status(), TransferEvent::Failed(err)),
+        };
+        self.last_status = Some(status);
+        endpoint.write_packet(&[XFER_DONE, status], storage);
+        event
+    }
+
+    // Replies to a CHUNK or END which arrived while no blob is active.
+    fn send_last_status(&mut self, endpoint: &mut EndPoint, storage: &mut dyn Storage) {
+        let status = match self.last_status {
+            Some(status) => status,
+            None => TransferError::NotStarted.to_status(),
+        };
+        debug!("Repeating transfer status {}", status);
+        endpoint.write_packet(&[XFER_DONE, status], storage);
+    }
+
+    /// Processes a user packet received from the BlobSender, replying as
+    /// required.
+    pub fn handle_packet(
+        &mut self,
+        data: &[u8],
+        endpoint: &mut EndPoint,
+        storage: &mut dyn Storage,
+    ) -> Option<TransferEvent> {
+        match data.first() {
+            Some(&XFER_START) => {
+                let len = get_u32(data, 1)?;
+                let expected_crc = get_u32(data, 5)?;
+                let name = &data[START_HEADER_SIZE..];
+                let name_crc = Crc32::new().accum_bytes(name);
+                let resume = self.active
+                    && self.name_crc == name_crc
+                    && self.len == len
+                    && self.expected_crc == expected_crc;
+                if !resume {
+                    if let Err(err) = self.sink.start(name, len) {
+                        return Some(self.finish(Err(err), endpoint, storage));
+                    }
+                    self.active = true;
+                    self.name_crc = name_crc;
+                    self.len = len;
+                    self.expected_crc = expected_crc;
+                    self.offset = 0;
+                    self.crc.reset();
+                }
+                debug!("Accepting transfer at offset {}", self.offset);
+                self.send_accept(endpoint, storage);
+                Some(self.progress())
+            }
+            Some(&XFER_CHUNK) if self.active => {
+                let offset = get_u32(data, 1)?;
+                let chunk = &data[CHUNK_HEADER_SIZE..];
+                if offset != self.offset {
+                    // Duplicate or missing data. Tell the sender where to
+                    // continue from.
+                    warn!(
+                        "Transfer chunk at offset {} expected {}",
+                        offset, self.offset
+                    );
+                    self.send_accept(endpoint, storage);
+                    return None;
+                }
+                if self.offset as usize + chunk.len() > self.len as usize {
+                    return Some(self.finish(
+                        Err(TransferError::LengthMismatch),
+                        endpoint,
+                        storage,
+                    ));
+                }
+                if let Err(err) = self.sink.write(offset, chunk) {
+                    return Some(self.finish(Err(err), endpoint, storage));
+                }
+                self.crc.accum_bytes(chunk);
+                self.offset += chunk.len() as u32;
+                Some(self.progress())
+            }
+            Some(&XFER_END) if self.active => {
+                let result = if self.offset != self.len {
+                    Err(TransferError::LengthMismatch)
+                } else if self.crc.crc() != self.expected_crc {
+                    Err(TransferError::CrcMismatch)
+                } else {
+                    Ok(())
+                };
+                Some(self.finish(result, endpoint, storage))
+            }
+            Some(&XFER_CHUNK) | Some(&XFER_END) => {
+                self.send_last_status(endpoint, storage);
+                None
+            }
+            _ => {
+                warn!("Dropping unexpected transfer packet");
+                None
+            }
+        }
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{connect_endpoints, setup_log, TestStorage};
+    use crate::ParseResult;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct TestSink {
+        name: Vec<u8>,
+        data: Vec<u8>,
+        result: Option<Result<(), TransferError>>,
+    }
+
+    impl BlobSink for TestSink {
+        fn start(&mut self, name: &[u8], len: u32) -> Result<(), TransferError> {
+            self.name = name.to_vec();
+            self.data = vec![0; len as usize];
+            self.result = None;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), TransferError> {
+            let offset = offset as usize;
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self, result: Result<(), TransferError>) {
+            self.result = Some(result);
+        }
+    }
+
+    struct Link {
+        ep1: EndPoint,
+        storage1: TestStorage,
+        ep2: EndPoint,
+        storage2: TestStorage,
+    }
+
+    impl Link {
+        fn new() -> Self {
+            let mut link = Link {
+                ep1: EndPoint::new(),
+                storage1: TestStorage::new(),
+                ep2: EndPoint::new(),
+                storage2: TestStorage::new(),
+            };
+            link.connect();
+            link
+        }
+
+        fn connect(&mut self) {
+            connect_endpoints(
+                &mut self.ep1,
+                &mut self.storage1,
+                &mut self.ep2,
+                &mut self.storage2,
+            );
+        }
+
+        // Delivers the last packet sent by the sender to the receiver.
+        fn deliver_to_receiver(&mut self, receiver: &mut BlobReceiver) -> Option<TransferEvent> {
+            assert_eq!(
+                self.ep2
+                    .parse_packet(self.storage1.tx_data(), &mut self.storage2),
+                ParseResult::UserPacket
+            );
+            let data = self.storage2.rx_data().to_vec();
+            receiver.handle_packet(&data, &mut self.ep2, &mut self.storage2)
+        }
+
+        // Delivers the last packet sent by the receiver to the sender.
+        fn deliver_to_sender(&mut self, sender: &mut BlobSender) -> Option<TransferEvent> {
+            assert_eq!(
+                self.ep1
+                    .parse_packet(self.storage2.tx_data(), &mut self.storage1),
+                ParseResult::UserPacket
+            );
+            sender.handle_packet(self.storage1.rx_data())
+        }
+    }
+
+    fn blob() -> Vec<u8> {
+        (0..1000u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn test_transfer() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 100];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"image.bin", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Progress {
+                    offset: 0,
+                    len: 1000
+                })
+            );
+            link.deliver_to_sender(&mut sender);
+
+            let mut chunks = 0;
+            while let Some(progress) = sender.poll(&mut link.ep1, &mut link.storage1) {
+                chunks += 1;
+                assert_eq!(link.deliver_to_receiver(&mut receiver), Some(progress));
+            }
+            assert_eq!(chunks, 10);
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Complete)
+            );
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Complete)
+            );
+            assert!(sender.is_done());
+        }
+        assert_eq!(sink.name, b"image.bin");
+        assert_eq!(sink.data, data);
+        assert_eq!(sink.result, Some(Ok(())));
+    }
+
+    #[test]
+    fn test_resume() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 128];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"log", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            link.deliver_to_receiver(&mut receiver);
+            link.deliver_to_sender(&mut sender);
+            for _ in 0..3 {
+                sender.poll(&mut link.ep1, &mut link.storage1);
+                link.deliver_to_receiver(&mut receiver);
+            }
+            // This chunk gets lost when the link goes down.
+            sender.poll(&mut link.ep1, &mut link.storage1);
+
+            link.connect();
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Progress {
+                    offset: 384,
+                    len: 1000
+                })
+            );
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Progress {
+                    offset: 384,
+                    len: 1000
+                })
+            );
+            while sender.poll(&mut link.ep1, &mut link.storage1).is_some() {
+                link.deliver_to_receiver(&mut receiver);
+            }
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Complete)
+            );
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Complete)
+            );
+        }
+        assert_eq!(sink.data, data);
+    }
+
+    // A source whose data changes after the sender has calculated the CRC.
+    struct ChangingSource {
+        reads: usize,
+    }
+
+    impl BlobSource for ChangingSource {
+        fn len(&self) -> u32 {
+            16
+        }
+
+        fn read(&mut self, _offset: u32, buf: &mut [u8]) -> usize {
+            self.reads += 1;
+            for byte in buf.iter_mut() {
+                *byte = self.reads as u8;
+            }
+            buf.len()
+        }
+    }
+
+    #[test]
+    fn test_crc_mismatch() {
+        setup_log();
+        let mut link = Link::new();
+        let mut source = ChangingSource { reads: 0 };
+        let mut chunk_buf = [0u8; 16];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"bad", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            link.deliver_to_receiver(&mut receiver);
+            link.deliver_to_sender(&mut sender);
+            sender.poll(&mut link.ep1, &mut link.storage1);
+            link.deliver_to_receiver(&mut receiver);
+            assert_eq!(sender.poll(&mut link.ep1, &mut link.storage1), None);
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Failed(TransferError::CrcMismatch))
+            );
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Failed(TransferError::CrcMismatch))
+            );
+        }
+        assert_eq!(sink.result, Some(Err(TransferError::CrcMismatch)));
+    }
+
+    // A source which fills the entire buffer, ignoring its own length.
+    struct GreedySource;
+
+    impl BlobSource for GreedySource {
+        fn len(&self) -> u32 {
+            10
+        }
+
+        fn read(&mut self, _offset: u32, buf: &mut [u8]) -> usize {
+            for byte in buf.iter_mut() {
+                *byte = 0xaa;
+            }
+            buf.len()
+        }
+    }
+
+    #[test]
+    fn test_read_limited_to_len() {
+        setup_log();
+        let mut link = Link::new();
+        let mut source = GreedySource;
+        let mut chunk_buf = [0u8; 64];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"short", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            link.deliver_to_receiver(&mut receiver);
+            link.deliver_to_sender(&mut sender);
+            assert_eq!(
+                sender.poll(&mut link.ep1, &mut link.storage1),
+                Some(TransferEvent::Progress {
+                    offset: 10,
+                    len: 10
+                })
+            );
+            link.deliver_to_receiver(&mut receiver);
+            assert_eq!(sender.poll(&mut link.ep1, &mut link.storage1), None);
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Complete)
+            );
+        }
+        assert_eq!(sink.data, [0xaa; 10]);
+        assert_eq!(sink.result, Some(Ok(())));
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 100];
+        let name = [b'x'; 256 - START_HEADER_SIZE + 1];
+        let mut sender = BlobSender::new(&name, &mut source, &mut chunk_buf);
+        let before = link.storage1.tx_vec();
+        assert_eq!(
+            sender.start(&mut link.ep1, &mut link.storage1),
+            Err(TransferError::NameTooLong)
+        );
+        assert_eq!(link.storage1.tx_data(), &before[..]);
+
+        let mut sender = BlobSender::new(&name[..16], &mut source, &mut chunk_buf);
+        assert_eq!(sender.start(&mut link.ep1, &mut link.storage1), Ok(()));
+    }
+
+    #[test]
+    fn test_chunk_too_long() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 256 - CHUNK_HEADER_SIZE + 1];
+        let mut sender = BlobSender::new(b"big", &mut source, &mut chunk_buf);
+        let before = link.storage1.tx_vec();
+        assert_eq!(
+            sender.start(&mut link.ep1, &mut link.storage1),
+            Err(TransferError::ChunkTooLong)
+        );
+        assert_eq!(link.storage1.tx_data(), &before[..]);
+
+        let mut sender = BlobSender::new(b"big", &mut source, &mut chunk_buf[1..]);
+        assert_eq!(sender.start(&mut link.ep1, &mut link.storage1), Ok(()));
+    }
+
+    #[test]
+    fn test_done_lost() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 200];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"lost", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            link.deliver_to_receiver(&mut receiver);
+            link.deliver_to_sender(&mut sender);
+            while sender.poll(&mut link.ep1, &mut link.storage1).is_some() {
+                link.deliver_to_receiver(&mut receiver);
+            }
+            assert_eq!(
+                link.deliver_to_receiver(&mut receiver),
+                Some(TransferEvent::Complete)
+            );
+
+            // The DONE never reaches the sender, so the END (and a stale
+            // chunk) are sent again. The receiver repeats its status each
+            // time.
+            link.ep1
+                .parse_packet(link.storage2.tx_data(), &mut link.storage1);
+            link.ep1.write_packet(&[XFER_END], &mut link.storage1);
+            assert_eq!(link.deliver_to_receiver(&mut receiver), None);
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Complete)
+            );
+            assert!(sender.is_done());
+
+            let mut hdr = [XFER_CHUNK; CHUNK_HEADER_SIZE];
+            hdr[1..5].copy_from_slice(&200u32.to_le_bytes());
+            link.ep1
+                .write_packet_vectored(&[&hdr, &data[200..400]], &mut link.storage1);
+            assert_eq!(link.deliver_to_receiver(&mut receiver), None);
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Complete)
+            );
+        }
+        assert_eq!(sink.result, Some(Ok(())));
+    }
+
+    #[test]
+    fn test_not_started() {
+        setup_log();
+        let mut link = Link::new();
+        let data = blob();
+        let mut source: &[u8] = &data;
+        let mut chunk_buf = [0u8; 100];
+        let mut sink = TestSink::default();
+        {
+            let mut sender = BlobSender::new(b"orphan", &mut source, &mut chunk_buf);
+            let mut receiver = BlobReceiver::new(&mut sink);
+
+            // The receiver never handled the START, e.g. because it
+            // restarted after accepting the blob.
+            sender.start(&mut link.ep1, &mut link.storage1).unwrap();
+            link.deliver_to_receiver(&mut BlobReceiver::new(&mut TestSink::default()));
+            link.deliver_to_sender(&mut sender);
+            sender.poll(&mut link.ep1, &mut link.storage1);
+            assert_eq!(link.deliver_to_receiver(&mut receiver), None);
+            assert_eq!(
+                link.deliver_to_sender(&mut sender),
+                Some(TransferEvent::Failed(TransferError::NotStarted))
+            );
+        }
+        assert_eq!(sink.result, None);
+    }
+}
@@ -0,0 +1,227 @@
+use core::cmp::min;
+use std::vec::Vec;
+
+use crate::crc::CRC_LEN;
+use crate::traits::{PacketBuffer, PacketQueue, PacketWriter, Storage};
+
+/// A PacketBuffer which allocates its storage on the heap. This is
+/// convenient on hosts where the packet size is only known at runtime.
+#[derive(Clone)]
+pub struct VecPacketBuffer {
+    len: usize,
+    buf: Vec<u8>,
+}
+
+impl VecPacketBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            len: 0,
+            buf: vec![0; capacity],
+        }
+    }
+}
+
+impl PacketBuffer for VecPacketBuffer {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = min(len, self.buf.len());
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+}
+
+/// A PacketQueue which allocates its packets on the heap.
+#[derive(Clone)]
+pub struct VecPacketQueue {
+    len: usize,
+    idx: usize,
+    packet: Vec<VecPacketBuffer>,
+}
+
+impl VecPacketQueue {
+    /// Creates a queue which can hold `capacity` packets, each of which can
+    /// hold `packet_size` bytes.
+    pub fn new(capacity: usize, packet_size: usize) -> Self {
+        Self {
+            len: 0,
+            idx: 0,
+            packet: (0..capacity)
+                .map(|_| VecPacketBuffer::new(packet_size))
+                .collect(),
+        }
+    }
+}
+
+impl PacketQueue for VecPacketQueue {
+    fn capacity(&self) -> usize {
+        self.packet.len()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = min(len, self.packet.len());
+    }
+
+    fn idx(&self) -> usize {
+        self.idx
+    }
+
+    fn set_idx(&mut self, idx: usize) {
+        self.idx = idx;
+    }
+
+    fn packet(&mut self, idx: usize) -> Option<&mut dyn PacketBuffer> {
+        match self.packet.get_mut(idx) {
+            Some(packet) => Some(packet),
+            None => None,
+        }
+    }
+}
+
+/// Written bytes are appended to the end of the vector.
+impl PacketWriter for Vec<u8> {
+    fn write_byte(&mut self, byte: u8) {
+        self.push(byte);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+/// A Storage implementation which allocates everything on the heap. Bytes
+/// written by the EndPoint accumulate in a transmit buffer until they're
+/// consumed by the caller.
+#[derive(Clone)]
+pub struct VecStorage {
+    rx_buf: VecPacketBuffer,
+    tx_buf: Vec<u8>,
+    tx_queue: VecPacketQueue,
+}
+
+impl VecStorage {
+    /// Creates storage for packets of up to `packet_size` bytes, keeping the
+    /// `history_len` most recently sent packets for retransmission.
+    pub fn new(packet_size: usize, history_len: usize) -> Self {
+        Self::with_rx_capacity(packet_size, history_len, packet_size + CRC_LEN)
+    }
+
+    /// Like `new`, but with a receive buffer of `rx_capacity` bytes (which
+    /// includes the CRC) rather than one sized to match the packets sent.
+    pub fn with_rx_capacity(packet_size: usize, history_len: usize, rx_capacity: usize) -> Self {
+        Self {
+            rx_buf: VecPacketBuffer::new(rx_capacity),
+            tx_buf: Vec::new(),
+            tx_queue: VecPacketQueue::new(history_len, packet_size),
+        }
+    }
+
+    /// Returns the most recently received packet.
+    pub fn rx_data(&self) -> &[u8] {
+        self.rx_buf.data()
+    }
+
+    /// Returns the bytes which have been written but not yet consumed.
+    pub fn tx_data(&self) -> &[u8] {
+        &self.tx_buf
+    }
+
+    /// Removes the first `len` bytes from the transmit buffer.
+    pub fn consume_tx_data(&mut self, len: usize) {
+        self.tx_buf.drain(..min(len, self.tx_buf.len()));
+    }
+
+    /// Removes and returns all of the bytes in the transmit buffer.
+    pub fn take_tx_data(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.tx_buf)
+    }
+}
+
+impl Storage for VecStorage {
+    fn rx_buf(&mut self) -> &mut dyn PacketBuffer {
+        &mut self.rx_buf
+    }
+
+    fn tx_writer(&mut self) -> &mut dyn PacketWriter {
+        &mut self.tx_buf
+    }
+
+    fn tx_queue(&mut self) -> &mut dyn PacketQueue {
+        &mut self.tx_queue
+    }
+}
+
+// ===========================================================================
+//
+// Tests
+//
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::VecDeque;
+
+    #[derive(Clone, Debug)]
+    enum QueueOp {
+        Push(u8),
+        Clear,
+    }
+
+    fn queue_op() -> impl Strategy<Value = QueueOp> {
+        prop_oneof![
+            10 => any::<u8>().prop_map(QueueOp::Push),
+            1 => Just(QueueOp::Clear),
+        ]
+    }
+
+    proptest! {
+        // The queue behaves like a ring which holds the `capacity` most
+        // recently pushed packets.
+        #[test]
+        fn prop_queue_ring(
+            capacity in 1usize..16,
+            ops in proptest::collection::vec(queue_op(), 0..100),
+        ) {
+            let mut queue = VecPacketQueue::new(capacity, 1);
+            // The most recently pushed packet is at the front.
+            let mut model = VecDeque::new();
+            for op in ops {
+                match op {
+                    QueueOp::Push(byte) => {
+                        queue.next().store_data(&[byte]);
+                        model.push_front(byte);
+                        model.truncate(capacity);
+                    }
+                    QueueOp::Clear => {
+                        queue.clear();
+                        model.clear();
+                    }
+                }
+                prop_assert_eq!(queue.len(), model.len());
+                prop_assert!(queue.idx() < capacity);
+                for offset in 0..capacity + 2 {
+                    let packet = queue.get(offset).map(|packet| packet.data().to_vec());
+                    prop_assert_eq!(packet, model.get(offset).map(|byte| vec![*byte]));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+-- Wireshark dissector for SFP frames written by serial_framing_protocol::pcapng
+-- (LINKTYPE_USER0). Generated by `sfp-decode --dissector`; do not edit.
+--
+-- Install by copying to the Wireshark personal plugins directory.
+
+local sfp = Proto("sfp", "Serial Framing Protocol")
+
+local FRAME_TYPE_MASK = 0xc0
+local SEQ_MASK = 0x3f
+local SYN = 0xc0
+local CRC_ERROR = 1
+local FLAG_HEADER = 0x01
+local PSEUDO_HEADER_LEN = 6
+
+local directions = { [0x00] = "Unknown", [0x01] = "A to B", [0x02] = "B to A" }
+local statuses = { [0x00] = "Good", [0x01] = "CRC error", [0x02] = "Aborted", [0x03] = "Runt", [0x04] = "Truncated" }
+local frame_types = { [0x00] = "USR", [0x40] = "RTX", [0x80] = "NAK", [0xc0] = "SYN" }
+local syn_codes = { [0x00] = "SYN0", [0x01] = "SYN1", [0x02] = "SYN2", [0x03] = "DIS" }
+
+local f = sfp.fields
+f.direction = ProtoField.uint8("sfp.direction", "Direction", base.DEC, directions)
+f.status = ProtoField.uint8("sfp.status", "Status", base.DEC, statuses)
+f.header = ProtoField.uint8("sfp.header", "Header", base.HEX)
+f.frame_type = ProtoField.uint8("sfp.type", "Frame type", base.HEX, frame_types, FRAME_TYPE_MASK)
+f.seq = ProtoField.uint8("sfp.seq", "Sequence number", base.DEC, nil, SEQ_MASK)
+f.syn = ProtoField.uint8("sfp.syn", "SYN code", base.DEC, syn_codes, SEQ_MASK)
+f.crc = ProtoField.uint16("sfp.crc", "Received CRC", base.HEX)
+f.payload = ProtoField.bytes("sfp.payload", "Payload")
+
+function sfp.dissector(buf, pinfo, tree)
+    if buf:len() < PSEUDO_HEADER_LEN then
+        return 0
+    end
+    pinfo.cols.protocol = "SFP"
+    local t = tree:add(sfp, buf())
+    t:add(f.direction, buf(0, 1))
+    t:add(f.status, buf(1, 1))
+
+    local info = "---"
+    if bit.band(buf(2, 1):uint(), FLAG_HEADER) ~= 0 then
+        local header = buf(3, 1)
+        local frame_type = bit.band(header:uint(), FRAME_TYPE_MASK)
+        local seq = bit.band(header:uint(), SEQ_MASK)
+        t:add(f.header, header)
+        t:add(f.frame_type, header)
+        if frame_type == SYN then
+            t:add(f.syn, header)
+            info = syn_codes[seq] or ("SYN code=" .. seq)
+        else
+            t:add(f.seq, header)
+            info = frame_types[frame_type] .. " seq=" .. seq
+        end
+    end
+
+    local len = buf:len() - PSEUDO_HEADER_LEN
+    if len > 0 then
+        t:add(f.payload, buf(PSEUDO_HEADER_LEN))
+        info = info .. " len=" .. len
+    end
+    local status = buf(1, 1):uint()
+    if status == CRC_ERROR then
+        t:add_le(f.crc, buf(4, 2))
+        info = info .. string.format(" CRC error (received 0x%04x)", buf(4, 2):le_uint())
+    elseif status ~= 0 then
+        info = info .. " " .. (statuses[status] or "status " .. status)
+    end
+    pinfo.cols.info = info
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, sfp)